@@ -1,7 +1,9 @@
+use crate::message::Payload;
+use crate::order;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "sqlx")]
-use sqlx::{FromRow, Type};
+use sqlx::{FromRow, SqlitePool, Type};
 #[cfg(feature = "sqlx")]
 use sqlx_crud::SqlxCrud;
 use std::{fmt::Display, str::FromStr};
@@ -25,6 +27,39 @@ pub enum Status {
     Released,
 }
 
+impl Status {
+    /// Every status a dispute can have
+    pub fn all() -> &'static [Status] {
+        &[
+            Status::Initiated,
+            Status::InProgress,
+            Status::SellerRefunded,
+            Status::Settled,
+            Status::Released,
+        ]
+    }
+
+    /// Position of the status in the dispute lifecycle, resolutions share
+    /// the same rank as only one of them can happen
+    pub fn rank(&self) -> u8 {
+        match self {
+            Status::Initiated => 0,
+            Status::InProgress => 1,
+            Status::SellerRefunded | Status::Settled => 2,
+            Status::Released => 3,
+        }
+    }
+
+    /// Check if the dispute is over, either resolved by a solver
+    /// (refunded or settled) or released by the seller
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            Status::SellerRefunded | Status::Settled | Status::Released
+        )
+    }
+}
+
 impl Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -52,6 +87,11 @@ impl FromStr for Status {
     }
 }
 
+/// Lowest value of a dispute security token
+pub const TOKEN_MIN: u16 = 100;
+/// Highest value of a dispute security token
+pub const TOKEN_MAX: u16 = 999;
+
 /// Database representation of a dispute
 #[cfg_attr(feature = "sqlx", derive(FromRow, SqlxCrud), external_id)]
 #[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -66,6 +106,17 @@ pub struct Dispute {
     pub seller_token: Option<u16>,
 }
 
+/// Dispute as seen by one of the order participants, without solver data
+/// nor the counterpart token
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ParticipantDisputeView {
+    pub id: Uuid,
+    pub order_id: Uuid,
+    pub status: String,
+    pub created_at: i64,
+    pub token: Option<u16>,
+}
+
 impl Dispute {
     pub fn new(order_id: Uuid) -> Self {
         Self {
@@ -79,4 +130,161 @@ impl Dispute {
             seller_token: None,
         }
     }
+
+    /// Get the inclusive range of valid security tokens, so clients can
+    /// constrain the input without knowing the token
+    pub fn token_format() -> (u16, u16) {
+        (TOKEN_MIN, TOKEN_MAX)
+    }
+
+    /// Get the number of digits of a security token
+    pub fn token_digits() -> usize {
+        3
+    }
+
+    /// Check that the buyer and seller tokens differ, to be run when a
+    /// dispute is loaded from database
+    pub fn validate_tokens_distinct(&self) -> anyhow::Result<()> {
+        if self.buyer_token.is_some() && self.buyer_token == self.seller_token {
+            return Err(anyhow::anyhow!("Buyer and seller dispute tokens are equal"));
+        }
+
+        Ok(())
+    }
+
+    /// Check the consistency of a dispute loaded from database, returns
+    /// the first failed check
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.id.is_nil() || self.order_id.is_nil() {
+            return Err(anyhow::anyhow!("Dispute ids must be set"));
+        }
+        let status = Status::from_str(&self.status)
+            .map_err(|_| anyhow::anyhow!("Invalid dispute status {}", self.status))?;
+        match (self.buyer_token, self.seller_token) {
+            (None, None) => {}
+            (Some(buyer), Some(seller)) => {
+                for token in [buyer, seller] {
+                    if !(TOKEN_MIN..=TOKEN_MAX).contains(&token) {
+                        return Err(anyhow::anyhow!("Dispute token {token} is out of range"));
+                    }
+                }
+                self.validate_tokens_distinct()?;
+            }
+            _ => return Err(anyhow::anyhow!("Only one dispute token was generated")),
+        }
+        // Released by the seller can happen before or after a solver took it
+        let solver_required = match status {
+            Status::Initiated => Some(false),
+            Status::InProgress | Status::SellerRefunded | Status::Settled => Some(true),
+            Status::Released => None,
+        };
+        if let Some(required) = solver_required {
+            if required != self.solver_pubkey.is_some() {
+                return Err(anyhow::anyhow!(
+                    "Solver is not consistent with dispute status {status}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the token of the counterpart of the user who opened the dispute
+    pub fn counterpart_token(&self, is_buyer_dispute: bool) -> anyhow::Result<u16> {
+        let token = if is_buyer_dispute {
+            self.seller_token
+        } else {
+            self.buyer_token
+        };
+        match token {
+            Some(token) if (TOKEN_MIN..=TOKEN_MAX).contains(&token) => Ok(token),
+            Some(token) => Err(anyhow::anyhow!("Dispute token {token} is out of range")),
+            None => Err(anyhow::anyhow!("Dispute tokens were not generated")),
+        }
+    }
+
+    /// Get the seconds the dispute took to be resolved, None while it is open
+    pub fn resolution_time(&self, resolved_at: i64) -> Option<i64> {
+        let status = Status::from_str(&self.status).ok()?;
+        status
+            .is_terminal()
+            .then_some(resolved_at - self.created_at)
+    }
+
+    /// Get the view of the dispute for the buyer or the seller, only their
+    /// own token is included
+    pub fn participant_view(&self, is_buyer: bool) -> ParticipantDisputeView {
+        let token = if is_buyer {
+            self.buyer_token
+        } else {
+            self.seller_token
+        };
+        ParticipantDisputeView {
+            id: self.id,
+            order_id: self.order_id,
+            status: self.status.clone(),
+            created_at: self.created_at,
+            token,
+        }
+    }
+
+    /// Build the dispute payload, the token is the one of the party receiving it
+    pub fn to_payload(&self, token: Option<u16>) -> Payload {
+        Payload::Dispute(self.id, token)
+    }
+
+    /// Get the order status implied by the dispute outcome, None while the
+    /// dispute is not resolved
+    pub fn expected_order_status(&self) -> Option<order::Status> {
+        match Status::from_str(&self.status).ok()? {
+            Status::Settled => Some(order::Status::SettledByAdmin),
+            Status::SellerRefunded => Some(order::Status::CanceledByAdmin),
+            Status::Released => Some(order::Status::Success),
+            Status::Initiated | Status::InProgress => None,
+        }
+    }
+
+    /// Check if both security tokens were generated, they must be set,
+    /// nonzero and different from each other
+    pub fn tokens_generated(&self) -> bool {
+        match (self.buyer_token, self.seller_token) {
+            (Some(buyer), Some(seller)) => buyer != 0 && seller != 0 && buyer != seller,
+            _ => false,
+        }
+    }
+}
+
+/// Get the median resolution time of the resolved disputes, each one paired
+/// with the moment it was resolved
+pub fn median_resolution_time(disputes: &[(Dispute, i64)]) -> Option<i64> {
+    let mut times: Vec<i64> = disputes
+        .iter()
+        .filter_map(|(dispute, resolved_at)| dispute.resolution_time(*resolved_at))
+        .collect();
+    if times.is_empty() {
+        return None;
+    }
+    times.sort_unstable();
+    let middle = times.len() / 2;
+    if times.len() % 2 == 0 {
+        Some((times[middle - 1] + times[middle]) / 2)
+    } else {
+        Some(times[middle])
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl Dispute {
+    /// Get the dispute of an order, None if the order was never disputed
+    pub async fn find_by_order(
+        pool: &SqlitePool,
+        order_id: Uuid,
+    ) -> anyhow::Result<Option<Dispute>> {
+        let dispute = sqlx::query_as::<_, Dispute>("SELECT * FROM disputes WHERE order_id = ?")
+            .bind(order_id)
+            .fetch_optional(pool)
+            .await?;
+
+        anyhow::Ok(dispute)
+    }
 }
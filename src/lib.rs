@@ -11,8 +11,13 @@ pub const PROTOCOL_VER: u8 = 1;
 
 #[cfg(test)]
 mod test {
-    use crate::message::{Action, CantDoReason, Message, MessageKind, Payload, Peer};
-    use crate::order::{Kind, SmallOrder, Status};
+    use crate::dispute::Dispute;
+    use crate::message::{Action, CantDoReason, Capabilities, Message, MessageKind, Payload, Peer};
+    use crate::order::{
+        Kind, MasterPubkey, Order, Role, SmallOrder, SmallOrderPatch, Status, StuckThresholds,
+        TradePubkey,
+    };
+    use crate::user::User;
     use nostr_sdk::Keys;
     use uuid::uuid;
 
@@ -32,6 +37,14 @@ mod test {
         assert_ne!(Kind::Buy.to_string(), "active");
     }
 
+    #[test]
+    fn test_kind_counterpart() {
+        assert_eq!(Kind::Buy.counterpart(), Kind::Sell);
+        assert_eq!(Kind::Sell.counterpart(), Kind::Buy);
+        assert_eq!(Kind::Sell.taker_role(), Role::Buyer);
+        assert_eq!(Kind::Buy.taker_role(), Role::Seller);
+    }
+
     #[test]
     fn test_order_message() {
         let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
@@ -148,6 +161,7 @@ mod test {
             CantDoReason::InvalidPeer,
             CantDoReason::InvalidRating,
             CantDoReason::InvalidTextMessage,
+            CantDoReason::InvalidFiatCurrency,
             CantDoReason::InvalidOrderStatus,
             CantDoReason::InvalidPubkey,
             CantDoReason::InvalidParameters,
@@ -159,6 +173,7 @@ mod test {
             CantDoReason::OutOfRangeSatsAmount,
             CantDoReason::IsNotYourDispute,
             CantDoReason::NotFound,
+            CantDoReason::Unauthorized,
         ];
 
         for reason in reasons {
@@ -186,4 +201,2516 @@ mod test {
         assert!(message.verify());
         assert_eq!(message.as_json().unwrap(), cant_do.as_json().unwrap());
     }
+
+    #[test]
+    fn test_dispute_tokens_generated() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let mut dispute = Dispute::new(uuid);
+        assert!(!dispute.tokens_generated());
+
+        dispute.buyer_token = Some(123);
+        dispute.seller_token = Some(123);
+        assert!(!dispute.tokens_generated());
+
+        dispute.seller_token = Some(456);
+        assert!(dispute.tokens_generated());
+    }
+
+    #[test]
+    fn test_status_legacy_snake_case() {
+        use std::str::FromStr;
+
+        assert_eq!(
+            Status::from_str("waiting_payment"),
+            Status::from_str("waiting-payment")
+        );
+        assert_eq!(
+            Status::from_str("waiting_payment"),
+            Ok(Status::WaitingPayment)
+        );
+        let snake: Status = serde_json::from_str(r#""waiting_payment""#).unwrap();
+        let kebab: Status = serde_json::from_str(r#""waiting-payment""#).unwrap();
+        assert_eq!(snake, Status::WaitingPayment);
+        assert_eq!(kebab, Status::WaitingPayment);
+        assert_eq!(
+            serde_json::to_string(&snake).unwrap(),
+            r#""waiting-payment""#
+        );
+    }
+
+    #[test]
+    fn test_trade_index_required() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let order = SmallOrder::new(
+            None,
+            Some(Kind::Sell),
+            Some(Status::Pending),
+            100,
+            "eur".to_string(),
+            None,
+            None,
+            100,
+            "SEPA".to_string(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let new_order = Message::new_order(
+            None,
+            Some(1),
+            None,
+            Action::NewOrder,
+            Some(Payload::Order(order)),
+        );
+        assert!(!new_order.verify());
+
+        let admin_cancel =
+            Message::new_dispute(Some(uuid), Some(1), None, Action::AdminCancel, None);
+        assert!(admin_cancel.verify());
+    }
+
+    #[test]
+    fn test_order_is_stuck() {
+        let thresholds = StuckThresholds {
+            pending: 3600,
+            waiting_payment: 900,
+            waiting_buyer_invoice: 900,
+        };
+        let order = Order {
+            status: Status::Pending.to_string(),
+            created_at: 1_000_000,
+            ..Default::default()
+        };
+        assert!(order.is_stuck(1_000_000 + 3601, &thresholds));
+        assert!(!order.is_stuck(1_000_000 + 60, &thresholds));
+
+        let order = Order {
+            status: Status::WaitingPayment.to_string(),
+            created_at: 1_000_000,
+            taken_at: 1_003_000,
+            ..Default::default()
+        };
+        assert!(!order.is_stuck(1_003_000 + 600, &thresholds));
+        assert!(order.is_stuck(1_003_000 + 901, &thresholds));
+
+        let order = Order {
+            status: Status::Active.to_string(),
+            created_at: 1_000_000,
+            ..Default::default()
+        };
+        assert!(!order.is_stuck(2_000_000, &thresholds));
+    }
+
+    #[test]
+    fn test_small_order_canonical_json() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let build = || {
+            SmallOrder::new(
+                Some(uuid),
+                Some(Kind::Buy),
+                Some(Status::Pending),
+                0,
+                "usd".to_string(),
+                None,
+                None,
+                50,
+                "cash".to_string(),
+                2,
+                None,
+                None,
+                None,
+                Some(1627371434),
+                None,
+                None,
+                None,
+            )
+        };
+        let first = build().canonical_json().unwrap();
+        let second = build().canonical_json().unwrap();
+        assert_eq!(first, second);
+        assert!(first.starts_with(r#"{"amount":0,"buyer_invoice":null,"#));
+        assert!(first.contains(r#""seller_trade_pubkey":null"#));
+    }
+
+    #[test]
+    fn test_order_role_pubkey() {
+        let mut order = Order {
+            seller_pubkey: Some("seller".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(order.role_pubkey(Role::Seller), Some("seller"));
+        assert_eq!(order.role_pubkey(Role::Buyer), None);
+
+        order.set_role_pubkey(Role::Buyer, "buyer".to_string());
+        assert_eq!(order.role_pubkey(Role::Buyer), Some("buyer"));
+        assert_eq!(order.buyer_pubkey.as_deref(), Some("buyer"));
+    }
+
+    #[test]
+    fn test_dispute_expected_order_status() {
+        use crate::dispute::Status as DisputeStatus;
+
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let mut dispute = Dispute::new(uuid);
+        dispute.status = DisputeStatus::Settled.to_string();
+        assert_eq!(
+            dispute.expected_order_status(),
+            Some(Status::SettledByAdmin)
+        );
+        dispute.status = DisputeStatus::SellerRefunded.to_string();
+        assert_eq!(
+            dispute.expected_order_status(),
+            Some(Status::CanceledByAdmin)
+        );
+        dispute.status = DisputeStatus::InProgress.to_string();
+        assert_eq!(dispute.expected_order_status(), None);
+    }
+
+    #[test]
+    fn test_normalize_fiat_code() {
+        let mut order = SmallOrder {
+            fiat_code: " eur ".to_string(),
+            ..Default::default()
+        };
+        assert!(order.normalize_fiat_code().is_ok());
+        assert_eq!(order.fiat_code, "EUR");
+
+        order.fiat_code = "E UR".to_string();
+        assert_eq!(
+            order.normalize_fiat_code(),
+            Err(CantDoReason::InvalidFiatCurrency)
+        );
+    }
+
+    #[test]
+    fn test_status_terminal() {
+        let non_terminal = [
+            Status::Active,
+            Status::Dispute,
+            Status::FiatSent,
+            Status::SettledHoldInvoice,
+            Status::Pending,
+            Status::WaitingBuyerInvoice,
+            Status::WaitingPayment,
+        ];
+        for status in Status::terminal_statuses() {
+            assert!(status.is_terminal());
+        }
+        for status in non_terminal {
+            assert!(!status.is_terminal());
+        }
+    }
+
+    #[cfg(feature = "sqlx")]
+    async fn sqlite_pool() -> sqlx::SqlitePool {
+        // A single connection keeps the in-memory database alive and shared
+        sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    #[cfg(feature = "sqlx")]
+    #[tokio::test]
+    async fn test_dispute_find_by_order() {
+        let pool = sqlite_pool().await;
+        sqlx::query(
+            r#"CREATE TABLE disputes (
+                id BLOB PRIMARY KEY NOT NULL,
+                order_id BLOB NOT NULL,
+                status TEXT NOT NULL,
+                solver_pubkey TEXT,
+                created_at INTEGER NOT NULL,
+                taken_at INTEGER NOT NULL,
+                buyer_token INTEGER,
+                seller_token INTEGER
+            )"#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let disputed_order = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let other_order = uuid!("c81a6a2f-5d1f-4b53-8c66-9e0b0f6e7c11");
+        let dispute = Dispute::new(disputed_order);
+        sqlx::query("INSERT INTO disputes VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(dispute.id)
+            .bind(dispute.order_id)
+            .bind(&dispute.status)
+            .bind(&dispute.solver_pubkey)
+            .bind(dispute.created_at)
+            .bind(dispute.taken_at)
+            .bind(dispute.buyer_token)
+            .bind(dispute.seller_token)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let found = Dispute::find_by_order(&pool, disputed_order).await.unwrap();
+        assert_eq!(found, Some(dispute));
+        let not_found = Dispute::find_by_order(&pool, other_order).await.unwrap();
+        assert_eq!(not_found, None);
+    }
+
+    #[test]
+    fn test_user_record_rating() {
+        let mut user = User::new("pubkey".to_string(), 0, 0, 0, 0, 1);
+        for rating in [5, 3, 4, 5] {
+            user.record_rating(rating).unwrap();
+        }
+        assert_eq!(user.rating_histogram(), [0, 0, 1, 1, 2]);
+        assert_eq!(user.total_reviews, 4);
+        assert_eq!(user.total_rating, 4.25);
+        assert_eq!(user.min_rating, 3);
+        assert_eq!(user.max_rating, 5);
+        assert_eq!(user.last_rating, 5);
+        assert_eq!(user.record_rating(6), Err(CantDoReason::InvalidRating));
+
+        // Reviews from before the histogram keep their weight
+        let mut legacy = User {
+            total_reviews: 2,
+            total_rating: 2.0,
+            ..Default::default()
+        };
+        legacy.record_rating(5).unwrap();
+        assert_eq!(legacy.total_reviews, 3);
+        assert_eq!(legacy.total_rating, 3.0);
+        legacy.record_rating(5).unwrap();
+        assert_eq!(legacy.total_reviews, 4);
+        assert_eq!(legacy.total_rating, 3.5);
+        legacy.record_rating(1).unwrap();
+        assert_eq!(legacy.total_reviews, 5);
+        assert_eq!(legacy.total_rating, 3.0);
+    }
+
+    #[test]
+    fn test_user_decayed_rating() {
+        let user = User {
+            total_reviews: 10,
+            total_rating: 5.0,
+            ..Default::default()
+        };
+        assert_eq!(user.decayed_rating(0, 90), 5.0);
+        assert_eq!(user.decayed_rating(90, 90), 4.0);
+        assert!((user.decayed_rating(3650, 90) - 3.0).abs() < 1e-6);
+        assert_eq!(user.decayed_rating(3650, 0), 5.0);
+
+        // Bad ratings recover toward neutral too
+        let bad = User {
+            total_reviews: 2,
+            total_rating: 1.0,
+            ..user.clone()
+        };
+        assert!((bad.decayed_rating(3650, 90) - 3.0).abs() < 1e-6);
+
+        // Advisory only, the stored rating is kept
+        assert_eq!(user.total_rating, 5.0);
+        assert_eq!(User::default().decayed_rating(0, 90), 3.0);
+    }
+
+    #[test]
+    fn test_message_header_only() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let message = Message::new_dispute(
+            Some(uuid),
+            Some(1),
+            Some(2),
+            Action::Dispute,
+            Some(Payload::TextMessage("secret".to_string())),
+        );
+        let header = message.header_only();
+        assert!(matches!(header, Message::Dispute(_)));
+        let (inner, stripped) = (
+            message.get_inner_message_kind(),
+            header.get_inner_message_kind(),
+        );
+        assert!(stripped.payload.is_none());
+        assert_eq!(stripped.version, inner.version);
+        assert_eq!(stripped.id, inner.id);
+        assert_eq!(stripped.request_id, inner.request_id);
+        assert_eq!(stripped.trade_index, inner.trade_index);
+        assert_eq!(stripped.action, inner.action);
+    }
+
+    #[test]
+    fn test_validate_invoice_amount() {
+        // 7851 sats regtest invoice
+        let fixed = "lnbcrt78510n1pj48ugqpp5j0yz2uyjgu5mcwlj4ucfd2hxjw78lumqr6ra9ua4ptdmsx2gxmxssp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygsdq2d4hhxarjduxqrrss9qrsgqtfh3zqntjcdsgun3w73h7wy05tyw3c5scp2nvzx647edjxdcqjwkzmppmzyxcf45hs29ad6zfxpzjak6kcvgttgqwytsmh2n54wvp5gq79dqfv";
+        let amountless = "lnbcrt1pj48ugqpp5j0yz2uyjgu5mcwlj4ucfd2hxjw78lumqr6ra9ua4ptdmsx2gxmxssp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygsdq2d4hhxarjduxqrrss9qrsgqv4vh8s7tz9h00fa7mav0e35mhmj39eu4dw8kgtj758650v2mguzr9w4az5y4zmn47trupsmrt2re5tpdajulah63a9c5tjtw0jny3hgps6ys0y";
+        let mut order = SmallOrder {
+            amount: 7851,
+            buyer_invoice: Some(fixed.to_string()),
+            ..Default::default()
+        };
+        assert!(order.validate_invoice_amount().is_ok());
+
+        order.amount = 100;
+        assert_eq!(
+            order.validate_invoice_amount(),
+            Err(CantDoReason::InvalidAmount)
+        );
+
+        order.buyer_invoice = Some(amountless.to_string());
+        assert!(order.validate_invoice_amount().is_ok());
+
+        order.buyer_invoice = Some("lnbcrt1invalid".to_string());
+        assert_eq!(
+            order.validate_invoice_amount(),
+            Err(CantDoReason::InvalidInvoice)
+        );
+    }
+
+    #[test]
+    fn test_order_immutable_fields() {
+        let previous = Order {
+            id: uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23"),
+            kind: Kind::Buy.to_string(),
+            status: Status::Pending.to_string(),
+            creator_pubkey: "creator".to_string(),
+            created_at: 1627371434,
+            ..Default::default()
+        };
+        let mut order = previous.clone();
+        order.status = Status::Active.to_string();
+        assert!(order.check_immutable_fields(&previous).is_ok());
+
+        order.kind = Kind::Sell.to_string();
+        assert_eq!(
+            order.check_immutable_fields(&previous),
+            Err(CantDoReason::InvalidParameters)
+        );
+    }
+
+    #[cfg(feature = "sqlx")]
+    async fn create_orders_table(pool: &sqlx::SqlitePool) {
+        sqlx::query(
+            r#"CREATE TABLE orders (
+                id BLOB PRIMARY KEY NOT NULL,
+                kind TEXT NOT NULL,
+                event_id TEXT NOT NULL DEFAULT '',
+                hash TEXT,
+                preimage TEXT,
+                creator_pubkey TEXT NOT NULL,
+                cancel_initiator_pubkey TEXT,
+                buyer_pubkey TEXT,
+                master_buyer_pubkey TEXT,
+                seller_pubkey TEXT,
+                master_seller_pubkey TEXT,
+                status TEXT NOT NULL,
+                price_from_api INTEGER NOT NULL DEFAULT 0,
+                premium INTEGER NOT NULL DEFAULT 0,
+                payment_method TEXT NOT NULL DEFAULT '',
+                amount INTEGER NOT NULL DEFAULT 0,
+                min_amount INTEGER,
+                max_amount INTEGER,
+                buyer_dispute INTEGER NOT NULL DEFAULT 0,
+                seller_dispute INTEGER NOT NULL DEFAULT 0,
+                buyer_cooperativecancel INTEGER NOT NULL DEFAULT 0,
+                seller_cooperativecancel INTEGER NOT NULL DEFAULT 0,
+                fee INTEGER NOT NULL DEFAULT 0,
+                routing_fee INTEGER NOT NULL DEFAULT 0,
+                fiat_code TEXT NOT NULL DEFAULT '',
+                fiat_amount INTEGER NOT NULL DEFAULT 0,
+                buyer_invoice TEXT,
+                range_parent_id BLOB,
+                invoice_held_at INTEGER NOT NULL DEFAULT 0,
+                taken_at INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL,
+                buyer_sent_rate INTEGER NOT NULL DEFAULT 0,
+                seller_sent_rate INTEGER NOT NULL DEFAULT 0,
+                failed_payment INTEGER NOT NULL DEFAULT 0,
+                payment_attempts INTEGER NOT NULL DEFAULT 0,
+                expires_at INTEGER NOT NULL DEFAULT 0,
+                trade_index_seller INTEGER,
+                trade_index_buyer INTEGER
+            )"#,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[cfg(feature = "sqlx")]
+    async fn insert_order(pool: &sqlx::SqlitePool, status: Status, creator: &str, created_at: i64) {
+        sqlx::query(
+            "INSERT INTO orders (id, kind, creator_pubkey, status, created_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(uuid::Uuid::new_v4())
+        .bind(Kind::Sell.to_string())
+        .bind(creator)
+        .bind(status.to_string())
+        .bind(created_at)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[cfg(feature = "sqlx")]
+    #[tokio::test]
+    async fn test_order_stream_by_creator() {
+        use futures::TryStreamExt;
+
+        let pool = sqlite_pool().await;
+        create_orders_table(&pool).await;
+        insert_order(&pool, Status::Pending, "alice", 1).await;
+        insert_order(&pool, Status::Active, "alice", 2).await;
+        insert_order(&pool, Status::Pending, "bob", 3).await;
+
+        let streamed: Vec<Order> = Order::stream_by_creator(&pool, "alice")
+            .try_collect()
+            .await
+            .unwrap();
+        let fetched: Vec<Order> = sqlx::query_as("SELECT * FROM orders WHERE creator_pubkey = ?")
+            .bind("alice")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(streamed.len(), 2);
+        let ids = |orders: &[Order]| orders.iter().map(|o| o.id).collect::<Vec<_>>();
+        assert_eq!(ids(&streamed), ids(&fetched));
+    }
+
+    #[test]
+    fn test_order_expiry_human() {
+        let now = 1_700_000_000;
+        let mut order = Order::default();
+        assert_eq!(order.expiry_human(now), "never");
+        order.expires_at = now - 1;
+        assert_eq!(order.expiry_human(now), "expired");
+        order.expires_at = now + 14 * 60 + 30;
+        assert_eq!(order.expiry_human(now), "14m");
+        order.expires_at = now + 2 * 3600 + 5 * 60;
+        assert_eq!(order.expiry_human(now), "2h 5m");
+        order.expires_at = now + 86400 + 3 * 3600;
+        assert_eq!(order.expiry_human(now), "1d 3h");
+    }
+
+    #[test]
+    fn test_order_typed_pubkeys() {
+        let order = Order {
+            buyer_pubkey: Some("buyer-trade".to_string()),
+            master_buyer_pubkey: Some("buyer-master".to_string()),
+            seller_pubkey: Some("seller-trade".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            order.trade_pubkey(Role::Buyer),
+            Some(TradePubkey("buyer-trade".to_string()))
+        );
+        assert_eq!(
+            order.master_pubkey(Role::Buyer),
+            Some(MasterPubkey("buyer-master".to_string()))
+        );
+        assert_eq!(
+            order.trade_pubkey(Role::Seller).unwrap().as_str(),
+            "seller-trade"
+        );
+        assert_eq!(order.master_pubkey(Role::Seller), None);
+        assert_eq!(
+            serde_json::to_string(&TradePubkey("key".to_string())).unwrap(),
+            r#""key""#
+        );
+    }
+
+    #[test]
+    fn test_dispute_to_payload() {
+        let dispute = Dispute::new(uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23"));
+        assert!(matches!(
+            dispute.to_payload(Some(123)),
+            Payload::Dispute(id, Some(123)) if id == dispute.id
+        ));
+        assert!(matches!(
+            dispute.to_payload(None),
+            Payload::Dispute(id, None) if id == dispute.id
+        ));
+    }
+
+    #[test]
+    fn test_check_above_dust() {
+        let mut order = SmallOrder {
+            amount: 354,
+            ..Default::default()
+        };
+        assert!(order.check_above_dust(354).is_ok());
+        order.amount = 1000;
+        assert!(order.check_above_dust(354).is_ok());
+        order.amount = 353;
+        assert_eq!(
+            order.check_above_dust(354),
+            Err(CantDoReason::OutOfRangeSatsAmount)
+        );
+        order.amount = 0;
+        assert!(order.check_above_dust(354).is_ok());
+    }
+
+    #[test]
+    fn test_capabilities_handshake() {
+        let capabilities = Capabilities {
+            cbor: true,
+            schema_version: 1,
+            max_protocol_version: 1,
+        };
+        let hello = Message::hello(Some(1), capabilities.clone());
+        assert!(hello.verify());
+        let json = hello.as_json().unwrap();
+        assert_eq!(
+            json,
+            r#"{"order":{"version":1,"request_id":1,"trade_index":null,"action":"hello","payload":{"capabilities":{"cbor":true,"schema_version":1,"max_protocol_version":1}}}}"#
+        );
+        let message = Message::from_json(&json).unwrap();
+        assert!(message.verify());
+        assert!(matches!(
+            message.get_inner_message_kind().get_payload(),
+            Some(Payload::Capabilities(c)) if *c == capabilities
+        ));
+
+        let empty_hello = Message::new_order(None, Some(1), None, Action::Hello, None);
+        assert!(!empty_hello.verify());
+    }
+
+    #[test]
+    fn test_order_range_parent_and_child() {
+        let parent = Order {
+            min_amount: Some(10),
+            max_amount: Some(100),
+            ..Default::default()
+        };
+        assert!(parent.is_range_parent());
+        assert!(!parent.is_range_child());
+
+        let child = Order {
+            min_amount: Some(10),
+            max_amount: Some(60),
+            range_parent_id: Some(uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23")),
+            ..Default::default()
+        };
+        assert!(!child.is_range_parent());
+        assert!(child.is_range_child());
+
+        let plain = Order::default();
+        assert!(!plain.is_range_parent());
+        assert!(!plain.is_range_child());
+    }
+
+    #[test]
+    fn test_total_locked_sats() {
+        let order = |status: Status, amount: i64| Order {
+            status: status.to_string(),
+            amount,
+            ..Default::default()
+        };
+        let orders = [
+            order(Status::Pending, 1000),
+            order(Status::Active, 2000),
+            order(Status::FiatSent, 3000),
+            order(Status::Dispute, 400),
+            order(Status::SettledHoldInvoice, 50),
+            order(Status::Success, 10000),
+            order(Status::WaitingPayment, 20000),
+            order(Status::WaitingBuyerInvoice, 30000),
+            Order {
+                invoice_held_at: 1700000000,
+                ..order(Status::WaitingBuyerInvoice, 600)
+            },
+        ];
+        assert_eq!(crate::order::total_locked_sats(&orders), 6050);
+    }
+
+    #[test]
+    fn test_kind_case_insensitive_deserialize() {
+        for (json, kind) in [
+            (r#""buy""#, Kind::Buy),
+            (r#""Buy""#, Kind::Buy),
+            (r#""SELL""#, Kind::Sell),
+            (r#""sElL""#, Kind::Sell),
+        ] {
+            assert_eq!(serde_json::from_str::<Kind>(json).unwrap(), kind);
+        }
+        assert!(serde_json::from_str::<Kind>(r#""swap""#).is_err());
+        assert_eq!(serde_json::to_string(&Kind::Sell).unwrap(), r#""sell""#);
+    }
+
+    #[test]
+    fn test_order_dispute_initiator_role() {
+        let mut order = Order {
+            buyer_dispute: true,
+            ..Default::default()
+        };
+        assert_eq!(order.dispute_initiator_role(), Ok(Some(Role::Buyer)));
+        order.buyer_dispute = false;
+        order.seller_dispute = true;
+        assert_eq!(order.dispute_initiator_role(), Ok(Some(Role::Seller)));
+        order.seller_dispute = false;
+        assert_eq!(order.dispute_initiator_role(), Ok(None));
+        order.buyer_dispute = true;
+        order.seller_dispute = true;
+        assert_eq!(
+            order.dispute_initiator_role(),
+            Err(CantDoReason::InvalidParameters)
+        );
+    }
+
+    #[test]
+    fn test_order_verify_preimage() {
+        let mut order = Order {
+            preimage: Some(
+                "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
+            ),
+            hash: Some(
+                "66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925".to_string(),
+            ),
+            ..Default::default()
+        };
+        assert!(order.verify_preimage().is_ok());
+
+        order.preimage =
+            Some("0000000000000000000000000000000000000000000000000000000000000001".to_string());
+        assert!(order.verify_preimage().is_err());
+
+        order.preimage = None;
+        assert!(order.verify_preimage().is_ok());
+    }
+
+    #[test]
+    fn test_cancel_message() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let initiator = "npub1testjsf0runcqdht5apkfcalajxkf8txdxqqk5kgm0agc38ke4vsfsgzf8";
+        let cancel = Message::new_cancel(uuid, Some(1), Some(2), Some(initiator.to_string()));
+        assert!(cancel.verify());
+        assert!(matches!(
+            cancel.get_inner_message_kind().get_payload(),
+            Some(Payload::Peer(peer)) if peer.pubkey == initiator
+        ));
+
+        let cancel = Message::new_cancel(uuid, Some(1), Some(2), None);
+        assert!(cancel.verify());
+        assert!(cancel.get_inner_message_kind().get_payload().is_none());
+
+        let cancel = Message::new_order(
+            Some(uuid),
+            Some(1),
+            Some(2),
+            Action::Cancel,
+            Some(Payload::TextMessage("bye".to_string())),
+        );
+        assert!(!cancel.verify());
+    }
+
+    #[test]
+    fn test_check_known_payment_methods() {
+        let mut order = SmallOrder {
+            payment_method: "sepa, Revolut".to_string(),
+            ..Default::default()
+        };
+        assert!(order.check_known_payment_methods(true).is_ok());
+        assert!(order.check_known_payment_methods(false).is_ok());
+
+        order.payment_method = "SEPA,carrier pigeon".to_string();
+        assert_eq!(
+            order.check_known_payment_methods(true),
+            Err(CantDoReason::InvalidParameters)
+        );
+        assert!(order.check_known_payment_methods(false).is_ok());
+    }
+
+    #[test]
+    fn test_dispute_token_format() {
+        assert_eq!(Dispute::token_format(), (100, 999));
+        assert_eq!(Dispute::token_digits(), 3);
+        let (min, max) = Dispute::token_format();
+        assert_eq!(min.to_string().len(), Dispute::token_digits());
+        assert_eq!(max.to_string().len(), Dispute::token_digits());
+    }
+
+    #[test]
+    fn test_dispute_status_rank() {
+        use crate::dispute::Status as DisputeStatus;
+
+        assert!(DisputeStatus::Initiated.rank() < DisputeStatus::InProgress.rank());
+        assert!(DisputeStatus::InProgress.rank() < DisputeStatus::Settled.rank());
+        assert_eq!(
+            DisputeStatus::Settled.rank(),
+            DisputeStatus::SellerRefunded.rank()
+        );
+        assert!(DisputeStatus::Settled.rank() < DisputeStatus::Released.rank());
+
+        assert!(!DisputeStatus::Initiated.is_terminal());
+        assert!(!DisputeStatus::InProgress.is_terminal());
+        assert!(DisputeStatus::SellerRefunded.is_terminal());
+        assert!(DisputeStatus::Settled.is_terminal());
+        assert!(DisputeStatus::Released.is_terminal());
+    }
+
+    #[test]
+    fn test_order_reason_for_disallowed() {
+        let mut order = Order {
+            kind: Kind::Sell.to_string(),
+            status: Status::Pending.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(order.reason_for_disallowed(&Action::TakeSell), None);
+        assert_eq!(
+            order.reason_for_disallowed(&Action::TakeBuy),
+            Some(CantDoReason::InvalidOrderKind)
+        );
+        assert_eq!(
+            order.reason_for_disallowed(&Action::FiatSent),
+            Some(CantDoReason::NotAllowedByStatus)
+        );
+
+        order.status = Status::Active.to_string();
+        assert_eq!(order.reason_for_disallowed(&Action::FiatSent), None);
+        assert_eq!(
+            order.reason_for_disallowed(&Action::TakeSell),
+            Some(CantDoReason::NotAllowedByStatus)
+        );
+
+        order.status = Status::Canceled.to_string();
+        assert_eq!(
+            order.reason_for_disallowed(&Action::Cancel),
+            Some(CantDoReason::OrderAlreadyCanceled)
+        );
+
+        order.status = "unknown".to_string();
+        assert_eq!(
+            order.reason_for_disallowed(&Action::Cancel),
+            Some(CantDoReason::InvalidOrderStatus)
+        );
+    }
+
+    #[test]
+    fn test_message_verify_signed() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let trade_keys =
+            Keys::parse("110e43647eae221ab1da33ddc17fd6ff423f2b2f49d809b9ffa40794a2ab996c")
+                .unwrap();
+        let other_keys = Keys::generate();
+        let message = Message::new_order(Some(uuid), Some(1), Some(2), Action::FiatSent, None);
+        let json = message.as_json().unwrap();
+        let sig = message.get_inner_message_kind().sign(&trade_keys);
+
+        let verified = Message::verify_signed(&json, trade_keys.public_key(), sig).unwrap();
+        assert_eq!(verified.as_json().unwrap(), json);
+        assert!(Message::verify_signed(&json, other_keys.public_key(), sig).is_err());
+
+        let invalid = Message::new_order(None, Some(1), Some(2), Action::FiatSent, None);
+        let sig = invalid.get_inner_message_kind().sign(&trade_keys);
+        let result =
+            Message::verify_signed(&invalid.as_json().unwrap(), trade_keys.public_key(), sig);
+        assert!(result.is_err());
+        assert!(Message::verify_signed("{}", trade_keys.public_key(), sig).is_err());
+    }
+
+    #[test]
+    fn test_check_premium_band() {
+        let mut order = SmallOrder::default();
+        for premium in [-10, 0, 10] {
+            order.premium = premium;
+            assert!(order.check_premium_band(-10, 10).is_ok());
+        }
+        for premium in [-11, 11, 1000] {
+            order.premium = premium;
+            assert_eq!(
+                order.check_premium_band(-10, 10),
+                Err(CantDoReason::InvalidParameters)
+            );
+        }
+    }
+
+    #[test]
+    fn test_order_book_delta() {
+        use crate::order::OrderBookDelta;
+
+        let order = |id, premium| SmallOrder {
+            id: Some(id),
+            kind: Some(Kind::Sell),
+            status: Some(Status::Pending),
+            premium,
+            ..Default::default()
+        };
+        let kept = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let changed = uuid!("c81a6a2f-5d1f-4b53-8c66-9e0b0f6e7c11");
+        let gone = uuid!("4f2d0c3e-9a7b-4c1d-8e5f-6a7b8c9d0e1f");
+        let fresh = uuid!("a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d");
+        let old = [order(kept, 1), order(changed, 1), order(gone, 1)];
+        let new = [order(kept, 1), order(changed, 3), order(fresh, 0)];
+
+        let delta = OrderBookDelta::diff(&old, &new);
+        assert_eq!(delta.added, vec![order(fresh, 0)]);
+        assert_eq!(delta.removed, vec![gone]);
+        assert_eq!(delta.updated, vec![order(changed, 3)]);
+    }
+
+    #[test]
+    fn test_dispute_counterpart_token() {
+        let mut dispute = Dispute::new(uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23"));
+        assert!(dispute.counterpart_token(true).is_err());
+
+        dispute.buyer_token = Some(123);
+        dispute.seller_token = Some(456);
+        assert_eq!(dispute.counterpart_token(true).unwrap(), 456);
+        assert_eq!(dispute.counterpart_token(false).unwrap(), 123);
+
+        dispute.seller_token = Some(42);
+        assert!(dispute.counterpart_token(true).is_err());
+    }
+
+    #[test]
+    fn test_validate_index_sequence() {
+        use crate::message::{validate_index_sequence, IndexSequenceError};
+
+        let invalid = |position| IndexSequenceError {
+            position,
+            reason: CantDoReason::InvalidTradeIndex,
+        };
+
+        let msg = |index| MessageKind::new(None, None, index, Action::TradePubkey, None);
+        let (first, second, third) = (msg(Some(1)), msg(Some(2)), msg(Some(5)));
+        assert!(validate_index_sequence(&[&first, &second, &third]).is_ok());
+
+        let repeated = msg(Some(2));
+        assert_eq!(
+            validate_index_sequence(&[&first, &second, &repeated]),
+            Err(invalid(2))
+        );
+        assert_eq!(
+            validate_index_sequence(&[&third, &second, &repeated]),
+            Err(invalid(1))
+        );
+        let missing = msg(None);
+        assert_eq!(
+            validate_index_sequence(&[&missing, &first]),
+            Err(invalid(0))
+        );
+    }
+
+    #[test]
+    fn test_message_kind_builder() {
+        let order = SmallOrder {
+            kind: Some(Kind::Buy),
+            status: Some(Status::Pending),
+            amount: 100,
+            fiat_code: "eur".to_string(),
+            fiat_amount: 10,
+            payment_method: "SEPA".to_string(),
+            ..Default::default()
+        };
+        let built = MessageKind::builder(Action::NewOrder)
+            .trade_index(1)
+            .payload(Payload::Order(order.clone()))
+            .build();
+        let expected = MessageKind::new(
+            None,
+            None,
+            Some(1),
+            Action::NewOrder,
+            Some(Payload::Order(order)),
+        );
+        assert!(built.verify());
+        assert_eq!(built.as_json().unwrap(), expected.as_json().unwrap());
+    }
+
+    #[test]
+    fn test_order_show_reputation() {
+        let order = Order {
+            buyer_pubkey: Some("buyer-trade".to_string()),
+            master_buyer_pubkey: Some("buyer-master".to_string()),
+            seller_pubkey: Some("seller-trade".to_string()),
+            master_seller_pubkey: Some("seller-trade".to_string()),
+            ..Default::default()
+        };
+        assert!(order.show_reputation(Role::Buyer));
+        assert!(!order.show_reputation(Role::Seller));
+
+        let order = Order {
+            buyer_pubkey: Some("buyer-trade".to_string()),
+            ..Default::default()
+        };
+        assert!(!order.show_reputation(Role::Buyer));
+    }
+
+    #[test]
+    fn test_order_status_enum() {
+        use std::str::FromStr;
+
+        let mut order = Order {
+            status: Status::WaitingBuyerInvoice.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            order.status_enum().ok(),
+            Status::from_str(&order.status).ok()
+        );
+        for _ in 0..10_000 {
+            assert_eq!(order.status_enum(), Ok(Status::WaitingBuyerInvoice));
+        }
+
+        order.status = "unknown".to_string();
+        assert_eq!(order.status_enum(), Err(CantDoReason::InvalidOrderStatus));
+    }
+
+    #[test]
+    fn test_validate_rating_target() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let rate = MessageKind::new(
+            Some(uuid),
+            Some(1),
+            Some(3),
+            Action::RateUser,
+            Some(Payload::RatingUser(5)),
+        );
+        let mut order = Order {
+            id: uuid,
+            status: Status::Success.to_string(),
+            buyer_pubkey: Some("buyer".to_string()),
+            seller_pubkey: Some("seller".to_string()),
+            ..Default::default()
+        };
+        assert!(rate.validate_rating_target(&order, "buyer").is_ok());
+        assert!(rate.validate_rating_target(&order, "seller").is_ok());
+        assert_eq!(
+            rate.validate_rating_target(&order, "stranger"),
+            Err(CantDoReason::IsNotYourOrder)
+        );
+
+        order.status = Status::FiatSent.to_string();
+        assert_eq!(
+            rate.validate_rating_target(&order, "buyer"),
+            Err(CantDoReason::NotAllowedByStatus)
+        );
+    }
+
+    #[test]
+    fn test_payload_tag_name() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let payloads = vec![
+            Payload::Order(SmallOrder::default()),
+            Payload::PaymentRequest(None, "lnbcrt1".to_string(), None),
+            Payload::TextMessage("hi".to_string()),
+            Payload::Peer(Peer::new("pubkey".to_string())),
+            Payload::RatingUser(5),
+            Payload::Amount(100),
+            Payload::Dispute(uuid, None),
+            Payload::CantDo(None),
+            Payload::Capabilities(Capabilities {
+                cbor: false,
+                schema_version: 1,
+                max_protocol_version: 1,
+            }),
+        ];
+        for payload in payloads {
+            let value = serde_json::to_value(&payload).unwrap();
+            let key = value.as_object().unwrap().keys().next().unwrap().clone();
+            assert_eq!(payload.tag_name(), key);
+        }
+    }
+
+    #[test]
+    fn test_order_apply_edit() {
+        let mut order = Order {
+            status: Status::Pending.to_string(),
+            seller_pubkey: Some("maker".to_string()),
+            amount: 1000,
+            premium: 1,
+            payment_method: "SEPA".to_string(),
+            fiat_amount: 10,
+            fiat_code: "EUR".to_string(),
+            ..Default::default()
+        };
+        let patch = SmallOrder {
+            amount: 2000,
+            premium: 3,
+            payment_method: "Revolut".to_string(),
+            fiat_amount: 20,
+            fiat_code: "USD".to_string(),
+            ..Default::default()
+        };
+        assert!(order.is_editable());
+        assert!(order.apply_edit(&patch).is_ok());
+        assert_eq!(order.amount, 2000);
+        assert_eq!(order.premium, 3);
+        assert_eq!(order.payment_method, "Revolut");
+        assert_eq!(order.fiat_amount, 20);
+        assert_eq!(order.fiat_code, "EUR");
+
+        let invalid = [
+            (
+                SmallOrder {
+                    amount: -1,
+                    ..patch.clone()
+                },
+                CantDoReason::InvalidAmount,
+            ),
+            (
+                SmallOrder {
+                    payment_method: " ".to_string(),
+                    ..patch.clone()
+                },
+                CantDoReason::InvalidParameters,
+            ),
+            (
+                SmallOrder {
+                    fiat_amount: 0,
+                    ..patch.clone()
+                },
+                CantDoReason::OutOfRangeFiatAmount,
+            ),
+        ];
+        for (invalid_patch, reason) in invalid {
+            assert_eq!(order.apply_edit(&invalid_patch), Err(reason));
+            assert_eq!(order.amount, 2000);
+            assert_eq!(order.payment_method, "Revolut");
+            assert_eq!(order.fiat_amount, 20);
+        }
+
+        // Range orders keep having no fiat amount
+        let mut range = Order {
+            min_amount: Some(10),
+            max_amount: Some(50),
+            fiat_amount: 0,
+            ..order.clone()
+        };
+        assert_eq!(
+            range.apply_edit(&patch),
+            Err(CantDoReason::OutOfRangeFiatAmount)
+        );
+        assert!(range
+            .apply_edit(&SmallOrder {
+                fiat_amount: 0,
+                ..patch.clone()
+            })
+            .is_ok());
+
+        order.buyer_pubkey = Some("taker".to_string());
+        order.status = Status::WaitingPayment.to_string();
+        assert!(!order.is_editable());
+        assert_eq!(
+            order.apply_edit(&patch),
+            Err(CantDoReason::NotAllowedByStatus)
+        );
+    }
+
+    #[test]
+    fn test_small_order_index_map() {
+        let order = SmallOrder {
+            kind: Some(Kind::Sell),
+            status: Some(Status::Pending),
+            amount: 1000,
+            fiat_code: "EUR".to_string(),
+            payment_method: "SEPA".to_string(),
+            premium: 2,
+            buyer_invoice: Some("lnbcrt1".to_string()),
+            ..Default::default()
+        };
+        let map = order.to_index_map();
+        assert_eq!(map.len(), 6);
+        assert_eq!(map["kind"], "sell");
+        assert_eq!(map["status"], "pending");
+        assert_eq!(map["fiat_code"], "EUR");
+        assert_eq!(map["payment_method"], "SEPA");
+        assert_eq!(map["premium"], "2");
+        assert_eq!(map["amount"], "1000");
+        assert!(!map.contains_key("buyer_invoice"));
+    }
+
+    #[test]
+    fn test_trade_key_path() {
+        use crate::message::trade_key_path;
+
+        assert_eq!(trade_key_path(0).unwrap(), "m/44'/1237'/38383'/0/0");
+        assert_eq!(trade_key_path(1).unwrap(), "m/44'/1237'/38383'/0/1");
+        assert_eq!(trade_key_path(42).unwrap(), "m/44'/1237'/38383'/0/42");
+        assert_eq!(trade_key_path(-1), Err(CantDoReason::InvalidTradeIndex));
+        assert_eq!(
+            trade_key_path(1 << 31),
+            Err(CantDoReason::InvalidTradeIndex)
+        );
+    }
+
+    #[test]
+    fn test_small_order_effective_fiat_amount() {
+        let fixed = SmallOrder {
+            fiat_amount: 50,
+            ..Default::default()
+        };
+        assert_eq!(fixed.effective_fiat_amount(None), Ok(50));
+        assert_eq!(fixed.effective_fiat_amount(Some(30)), Ok(50));
+
+        let range = SmallOrder {
+            min_amount: Some(10),
+            max_amount: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(range.effective_fiat_amount(Some(30)), Ok(30));
+        assert_eq!(range.effective_fiat_amount(Some(100)), Ok(100));
+        assert_eq!(
+            range.effective_fiat_amount(Some(101)),
+            Err(CantDoReason::OutOfRangeFiatAmount)
+        );
+        assert_eq!(
+            range.effective_fiat_amount(None),
+            Err(CantDoReason::InvalidAmount)
+        );
+    }
+
+    #[test]
+    fn test_check_payment_request_amount() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let order = SmallOrder {
+            id: Some(uuid),
+            amount: 7851,
+            ..Default::default()
+        };
+        let pay_invoice = |amount| {
+            MessageKind::new(
+                Some(uuid),
+                Some(1),
+                Some(2),
+                Action::PayInvoice,
+                Some(Payload::PaymentRequest(
+                    Some(order.clone()),
+                    "lnbcrt1".to_string(),
+                    amount,
+                )),
+            )
+        };
+        assert!(pay_invoice(Some(7851))
+            .check_payment_request_amount()
+            .is_ok());
+        assert_eq!(
+            pay_invoice(Some(100)).check_payment_request_amount(),
+            Err(CantDoReason::InvalidAmount)
+        );
+        assert!(pay_invoice(None).check_payment_request_amount().is_ok());
+    }
+
+    #[test]
+    fn test_verifier_context() {
+        use crate::message::VerifierContext;
+
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let trade_keys = Keys::generate();
+        let context = VerifierContext::default();
+        for (index, action) in [Action::FiatSent, Action::Release, Action::Dispute]
+            .into_iter()
+            .enumerate()
+        {
+            let message = MessageKind::new(Some(uuid), Some(1), Some(index as i64), action, None);
+            let sig = message.sign(&trade_keys);
+            let json = message.as_json().unwrap();
+            assert!(context.verify(&json, trade_keys.public_key(), sig));
+            assert!(!context.verify(&json, Keys::generate().public_key(), sig));
+        }
+    }
+
+    #[test]
+    fn test_order_set_event_id() {
+        let mut order = Order::default();
+        let event_id = "b3e392b11f5d4f28321cedd09303a748acfd0487aea5a7450b3481c60b6e4f87";
+        assert!(order.set_event_id(event_id).is_ok());
+        assert_eq!(order.event_id, event_id);
+
+        assert!(order.set_event_id("b3e392b11f5d4f28").is_err());
+        assert!(order
+            .set_event_id("z3e392b11f5d4f28321cedd09303a748acfd0487aea5a7450b3481c60b6e4f87")
+            .is_err());
+        assert_eq!(order.event_id, event_id);
+    }
+
+    #[test]
+    fn test_order_check_priced() {
+        let mut order = Order {
+            status: Status::Pending.to_string(),
+            price_from_api: true,
+            amount: 0,
+            ..Default::default()
+        };
+        assert!(order.check_priced().is_ok());
+
+        order.status = Status::Active.to_string();
+        order.amount = 7851;
+        assert!(order.check_priced().is_ok());
+
+        order.amount = 0;
+        assert_eq!(order.check_priced(), Err(CantDoReason::InvalidAmount));
+    }
+
+    #[test]
+    fn test_peer_p_tag() {
+        let keys = Keys::generate();
+        let peer = Peer::new(keys.public_key().to_hex());
+        let tag = peer.to_p_tag().unwrap();
+        assert_eq!(
+            tag.clone().to_vec(),
+            vec!["p".to_string(), peer.pubkey.clone()]
+        );
+        assert_eq!(Peer::from_p_tag(&tag).unwrap().pubkey, peer.pubkey);
+
+        let invalid = Peer::new("not a pubkey".to_string());
+        assert_eq!(invalid.to_p_tag(), Err(CantDoReason::InvalidPubkey));
+    }
+
+    #[test]
+    fn test_order_stats() {
+        use crate::order::OrderStats;
+
+        let order = |status: Status, amount: i64, fiat_code: &str, fiat_amount: i64| Order {
+            status: status.to_string(),
+            amount,
+            fiat_code: fiat_code.to_string(),
+            fiat_amount,
+            ..Default::default()
+        };
+        let orders = [
+            order(Status::Success, 1000, "USD", 10),
+            order(Status::Success, 2000, "usd", 40),
+            order(Status::Canceled, 500, "VES", 5000),
+            order(Status::Pending, 0, "USD", 20),
+        ];
+        let stats = OrderStats::compute(&orders);
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.by_status["success"], 2);
+        assert_eq!(stats.by_status["canceled"], 1);
+        assert_eq!(stats.by_status["pending"], 1);
+        assert_eq!(stats.completion_rate, 0.5);
+        assert_eq!(stats.total_amount, 3500);
+        assert_eq!(stats.median_fiat_amount.len(), 2);
+        assert_eq!(stats.median_fiat_amount["USD"], 20);
+        assert_eq!(stats.median_fiat_amount["VES"], 5000);
+
+        let empty = OrderStats::compute(&[]);
+        assert_eq!(empty.total, 0);
+        assert!(empty.median_fiat_amount.is_empty());
+    }
+
+    #[test]
+    fn test_dispute_validate_tokens_distinct() {
+        let mut dispute = Dispute::new(uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23"));
+        dispute.buyer_token = Some(123);
+        dispute.seller_token = Some(456);
+        assert!(dispute.validate_tokens_distinct().is_ok());
+
+        dispute.seller_token = Some(123);
+        assert!(dispute.validate_tokens_distinct().is_err());
+    }
+
+    #[test]
+    fn test_message_debug_safe() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let invoice = "lnbcrt78510n1pj48ugqpp5j0yz2uyjgu5mcwlj4ucfd2hxjw78lumqr6ra9ua4ptdmsx2gxmxs";
+        let keys = Keys::generate();
+        let pubkey = keys.public_key().to_string();
+        let order = SmallOrder {
+            id: Some(uuid),
+            buyer_trade_pubkey: Some(pubkey.clone()),
+            seller_trade_pubkey: Some(pubkey.clone()),
+            buyer_invoice: Some(invoice.to_string()),
+            buyer_token: Some(123),
+            ..Default::default()
+        };
+        let message = Message::new_order(
+            Some(uuid),
+            Some(1),
+            Some(2),
+            Action::PayInvoice,
+            Some(Payload::PaymentRequest(
+                Some(order),
+                invoice.to_string(),
+                Some(7851),
+            )),
+        );
+        let safe = message.debug_safe();
+        assert!(!safe.contains(invoice));
+        assert!(!safe.contains("123"));
+        assert!(safe.contains("[redacted]"));
+        assert!(safe.contains(&uuid.to_string()));
+        assert!(!safe.contains(&pubkey));
+        assert!(message.as_json().unwrap().contains(invoice));
+
+        let message = Message::new_order(
+            Some(uuid),
+            None,
+            None,
+            Action::Cancel,
+            Some(Payload::Peer(Peer::new(pubkey.clone()))),
+        );
+        let safe = message.debug_safe();
+        assert!(!safe.contains(&pubkey));
+        assert!(safe.contains("[redacted]"));
+        assert!(message.as_json().unwrap().contains(&pubkey));
+    }
+
+    #[test]
+    fn test_check_trade_index_sane() {
+        let msg = |index| MessageKind::new(None, None, Some(index), Action::TradePubkey, None);
+        assert!(msg(5).check_trade_index_sane(1_000_000).is_ok());
+        assert_eq!(
+            msg(-1).check_trade_index_sane(1_000_000),
+            Err(CantDoReason::InvalidTradeIndex)
+        );
+        assert_eq!(
+            msg(i64::MAX).check_trade_index_sane(1_000_000),
+            Err(CantDoReason::InvalidTradeIndex)
+        );
+    }
+
+    #[test]
+    fn test_action_is_terminal() {
+        assert!(Action::Released.is_terminal());
+        assert!(Action::PurchaseCompleted.is_terminal());
+        assert!(!Action::FiatSent.is_terminal());
+
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let released = Message::new_order(Some(uuid), None, None, Action::Released, None);
+        assert!(released.is_terminal());
+        let fiat_sent = Message::new_order(Some(uuid), None, None, Action::FiatSent, None);
+        assert!(!fiat_sent.is_terminal());
+    }
+
+    #[test]
+    fn test_small_order_check_fiat_amount() {
+        let mut fixed = SmallOrder {
+            fiat_amount: 100,
+            ..Default::default()
+        };
+        assert!(fixed.check_fiat_amount().is_ok());
+        fixed.fiat_amount = 0;
+        assert_eq!(
+            fixed.check_fiat_amount(),
+            Err(CantDoReason::OutOfRangeFiatAmount)
+        );
+
+        let mut range = SmallOrder {
+            min_amount: Some(10),
+            max_amount: Some(100),
+            ..Default::default()
+        };
+        assert!(range.check_fiat_amount().is_ok());
+        range.fiat_amount = 50;
+        assert_eq!(
+            range.check_fiat_amount(),
+            Err(CantDoReason::OutOfRangeFiatAmount)
+        );
+    }
+
+    #[test]
+    fn test_normalize_invoice() {
+        use crate::order::normalize_invoice;
+
+        let invoice = "lnbcrt78510n1pj48ugqpp5j0yz2uyjgu5mcwlj4ucfd2hxjw78lumqr6ra9ua4ptdmsx2gxmxssp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygsdq2d4hhxarjduxqrrss9qrsgqtfh3zqntjcdsgun3w73h7wy05tyw3c5scp2nvzx647edjxdcqjwkzmppmzyxcf45hs29ad6zfxpzjak6kcvgttgqwytsmh2n54wvp5gq79dqfv";
+        assert_eq!(normalize_invoice(invoice).unwrap(), invoice);
+        let prefixed = format!("  lightning:{}\n", invoice.to_uppercase());
+        assert_eq!(normalize_invoice(&prefixed).unwrap(), invoice);
+        assert_eq!(
+            normalize_invoice("lightning:lnbcrt1invalid"),
+            Err(CantDoReason::InvalidInvoice)
+        );
+
+        // Invoices are stored normalized
+        let order = SmallOrder::builder()
+            .fiat_code("USD")
+            .fiat_amount(10)
+            .buyer_invoice(prefixed.clone())
+            .build()
+            .unwrap();
+        assert_eq!(order.buyer_invoice.as_deref(), Some(invoice));
+
+        let mut message = MessageKind::new(
+            None,
+            Some(1),
+            Some(2),
+            Action::AddInvoice,
+            Some(Payload::PaymentRequest(None, prefixed, None)),
+        );
+        message.normalize_invoices().unwrap();
+        assert_eq!(
+            message.payload,
+            Some(Payload::PaymentRequest(None, invoice.to_string(), None))
+        );
+
+        let mut address = SmallOrder::default();
+        address.set_buyer_invoice(" alice@example.com ").unwrap();
+        assert_eq!(address.buyer_invoice.as_deref(), Some("alice@example.com"));
+        assert_eq!(
+            address.set_buyer_invoice("lnbcrt1invalid"),
+            Err(CantDoReason::InvalidInvoice)
+        );
+    }
+
+    #[test]
+    fn test_dispute_resolution_time() {
+        use crate::dispute::{median_resolution_time, Status as DisputeStatus};
+
+        let dispute = |status: DisputeStatus, created_at: i64| Dispute {
+            status: status.to_string(),
+            created_at,
+            ..Dispute::new(uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23"))
+        };
+        let settled = dispute(DisputeStatus::Settled, 1000);
+        assert_eq!(settled.resolution_time(4600), Some(3600));
+        let open = dispute(DisputeStatus::InProgress, 1000);
+        assert_eq!(open.resolution_time(4600), None);
+
+        let disputes = [
+            (settled, 4600),
+            (dispute(DisputeStatus::SellerRefunded, 2000), 3000),
+            (dispute(DisputeStatus::Released, 0), 7200),
+            (open, 100_000),
+        ];
+        assert_eq!(median_resolution_time(&disputes), Some(3600));
+        assert_eq!(median_resolution_time(&disputes[..2]), Some(2300));
+        assert_eq!(median_resolution_time(&[]), None);
+    }
+
+    #[test]
+    fn test_order_audit_record() {
+        let order = Order {
+            id: uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23"),
+            kind: Kind::Sell.to_string(),
+            status: Status::Active.to_string(),
+            buyer_invoice: Some("lnbcrt78510n1pj48ugq".to_string()),
+            preimage: Some("00".repeat(32)),
+            ..Default::default()
+        };
+        let record = order.audit_record("taken", "buyer|pubkey", 1700000000);
+        assert_eq!(
+            record,
+            "1700000000|308e1272-d5f4-47e6-bd97-3504baea9c23|sell|active|taken|buyer pubkey"
+        );
+        assert!(!record.contains("lnbcrt"));
+        assert!(!record.contains(&"00".repeat(32)));
+    }
+
+    #[test]
+    fn test_expected_action() {
+        use crate::message::expected_action;
+
+        let buyer_actions = expected_action(Status::WaitingPayment, Role::Buyer);
+        assert!(!buyer_actions.contains(&Action::FiatSent));
+        assert_eq!(buyer_actions, vec![Action::Cancel]);
+
+        let seller_actions = expected_action(Status::FiatSent, Role::Seller);
+        assert!(seller_actions.contains(&Action::Release));
+        assert!(!seller_actions.contains(&Action::FiatSent));
+
+        assert!(expected_action(Status::Active, Role::Buyer).contains(&Action::FiatSent));
+        assert!(expected_action(Status::Canceled, Role::Buyer).is_empty());
+        assert!(expected_action(Status::Active, Role::Seller).contains(&Action::Release));
+        assert!(
+            expected_action(Status::SettledHoldInvoice, Role::Buyer).contains(&Action::AddInvoice)
+        );
+
+        // Every expected action must be accepted by the order in that status
+        for &status in Status::all() {
+            for (role, kind) in [(Role::Buyer, Kind::Sell), (Role::Seller, Kind::Buy)] {
+                let order = Order {
+                    kind: kind.to_string(),
+                    status: status.to_string(),
+                    ..Default::default()
+                };
+                for action in expected_action(status, role) {
+                    assert_eq!(order.reason_for_disallowed(&action), None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_order_kind_status_consistency() {
+        let order = |kind: Kind, status: Status, buyer: bool, seller: bool| Order {
+            kind: kind.to_string(),
+            status: status.to_string(),
+            buyer_pubkey: buyer.then(|| "buyer".to_string()),
+            seller_pubkey: seller.then(|| "seller".to_string()),
+            ..Default::default()
+        };
+        let invalid = Err(CantDoReason::InvalidOrderStatus);
+
+        // A pending sell order only has the seller
+        assert!(order(Kind::Sell, Status::Pending, false, true)
+            .check_kind_status_consistency()
+            .is_ok());
+        for (buyer, seller) in [(true, false), (true, true), (false, false)] {
+            assert_eq!(
+                order(Kind::Sell, Status::Pending, buyer, seller).check_kind_status_consistency(),
+                invalid
+            );
+        }
+
+        // A pending buy order only has the buyer
+        assert!(order(Kind::Buy, Status::Pending, true, false)
+            .check_kind_status_consistency()
+            .is_ok());
+        for (buyer, seller) in [(false, true), (true, true), (false, false)] {
+            assert_eq!(
+                order(Kind::Buy, Status::Pending, buyer, seller).check_kind_status_consistency(),
+                invalid
+            );
+        }
+
+        // Taken orders have both sides whatever the kind
+        for kind in [Kind::Sell, Kind::Buy] {
+            assert!(order(kind, Status::Active, true, true)
+                .check_kind_status_consistency()
+                .is_ok());
+            assert_eq!(
+                order(kind, Status::Active, false, true).check_kind_status_consistency(),
+                invalid
+            );
+            assert!(order(kind, Status::Canceled, false, false)
+                .check_kind_status_consistency()
+                .is_ok());
+        }
+
+        let mut swap = order(Kind::Sell, Status::Active, true, true);
+        swap.kind = "swap".to_string();
+        assert_eq!(
+            swap.check_kind_status_consistency(),
+            Err(CantDoReason::InvalidOrderKind)
+        );
+    }
+
+    #[test]
+    fn test_order_creator_npub() {
+        let mut order = Order {
+            creator_pubkey: "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d"
+                .to_string(),
+            ..Default::default()
+        };
+        let npub = "npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6";
+        assert_eq!(order.creator_npub().unwrap(), npub);
+
+        order.creator_pubkey = npub.to_string();
+        assert_eq!(order.creator_npub().unwrap(), npub);
+
+        order.creator_pubkey = "not-a-key".to_string();
+        assert_eq!(order.creator_npub(), Err(CantDoReason::InvalidPubkey));
+    }
+
+    #[test]
+    fn test_small_order_merge_patch() {
+        let mut order = SmallOrder {
+            kind: Some(Kind::Sell),
+            status: Some(Status::Pending),
+            fiat_code: "VES".to_string(),
+            fiat_amount: 100,
+            payment_method: "face to face".to_string(),
+            premium: 1,
+            ..Default::default()
+        };
+        let mut expected = order.clone();
+        order.merge_patch(&SmallOrderPatch {
+            status: Some(Status::Canceled),
+            premium: Some(5),
+            ..Default::default()
+        });
+        expected.status = Some(Status::Canceled);
+        expected.premium = 5;
+        assert_eq!(order, expected);
+
+        order.merge_patch(&SmallOrderPatch::default());
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn test_dispute_validate() {
+        use crate::dispute::Status as DisputeStatus;
+
+        let mut dispute = Dispute::new(uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23"));
+        dispute.buyer_token = Some(123);
+        dispute.seller_token = Some(456);
+        assert!(dispute.validate().is_ok());
+
+        let mut invalid = dispute.clone();
+        invalid.order_id = uuid::Uuid::nil();
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = dispute.clone();
+        invalid.status = "closed".to_string();
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = dispute.clone();
+        invalid.seller_token = Some(123);
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = dispute.clone();
+        invalid.seller_token = Some(1000);
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = dispute.clone();
+        invalid.seller_token = None;
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = dispute.clone();
+        invalid.solver_pubkey = Some("solver".to_string());
+        assert!(invalid.validate().is_err());
+
+        let mut taken = dispute.clone();
+        taken.status = DisputeStatus::InProgress.to_string();
+        assert!(taken.validate().is_err());
+        taken.solver_pubkey = Some("solver".to_string());
+        assert!(taken.validate().is_ok());
+
+        let mut released = dispute;
+        released.status = DisputeStatus::Released.to_string();
+        assert!(released.validate().is_ok());
+    }
+
+    #[test]
+    fn test_small_order_from_events() {
+        use crate::NOSTR_REPLACEABLE_EVENT_KIND;
+        use nostr_sdk::{EventBuilder, Kind as NostrKind, Tag, TagKind};
+
+        let keys = Keys::generate();
+        let event = |tags: Vec<(&'static str, Vec<&str>)>| {
+            let tags = tags.into_iter().map(|(key, values)| {
+                Tag::custom(
+                    TagKind::Custom(std::borrow::Cow::Borrowed(key)),
+                    values.into_iter().map(String::from).collect::<Vec<_>>(),
+                )
+            });
+            EventBuilder::new(NostrKind::Custom(NOSTR_REPLACEABLE_EVENT_KIND), "")
+                .tags(tags)
+                .sign_with_keys(&keys)
+                .unwrap()
+        };
+        let events = vec![
+            event(vec![
+                ("d", vec!["308e1272-d5f4-47e6-bd97-3504baea9c23"]),
+                ("k", vec!["sell"]),
+                ("f", vec!["VES"]),
+                ("s", vec!["pending"]),
+                ("amt", vec!["0"]),
+                ("fa", vec!["100"]),
+                ("pm", vec!["face to face", "bank transfer"]),
+                ("premium", vec!["1"]),
+                ("expiration", vec!["1700000000"]),
+            ]),
+            event(vec![
+                ("d", vec!["308e1272-d5f4-47e6-bd97-3504baea9c24"]),
+                ("k", vec!["buy"]),
+                ("amt", vec!["many"]),
+            ]),
+            event(vec![
+                ("d", vec!["308e1272-d5f4-47e6-bd97-3504baea9c25"]),
+                ("k", vec!["buy"]),
+                ("f", vec!["USD"]),
+                ("fa", vec!["10", "50"]),
+                ("-", vec![]),
+            ]),
+        ];
+
+        let orders = SmallOrder::from_events(&events);
+        assert_eq!(orders.len(), 3);
+        let sell = orders[0].as_ref().unwrap();
+        assert_eq!(sell.id, Some(uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23")));
+        assert_eq!(sell.kind, Some(Kind::Sell));
+        assert_eq!(sell.status, Some(Status::Pending));
+        assert_eq!(sell.fiat_amount, 100);
+        assert_eq!(sell.payment_method, "face to face,bank transfer");
+        assert_eq!(sell.expires_at, Some(1700000000));
+        assert_eq!(sell.created_at, Some(events[0].created_at.as_u64() as i64));
+        assert!(orders[1].is_err());
+        let range = orders[2].as_ref().unwrap();
+        assert_eq!(range.min_amount, Some(10));
+        assert_eq!(range.max_amount, Some(50));
+    }
+
+    #[test]
+    fn test_check_unique_trade_indices() {
+        use crate::order::{check_unique_trade_indices, orders_of_user};
+
+        let order = |buyer: Option<i64>, seller: Option<i64>| Order {
+            status: Status::Active.to_string(),
+            trade_index_buyer: buyer,
+            trade_index_seller: seller,
+            ..Default::default()
+        };
+        let unique = vec![
+            order(Some(1), None),
+            order(None, Some(2)),
+            order(Some(3), None),
+        ];
+        assert!(check_unique_trade_indices(&unique).is_ok());
+        assert!(check_unique_trade_indices(&[]).is_ok());
+
+        let colliding = vec![order(Some(1), None), order(None, Some(1))];
+        assert_eq!(
+            check_unique_trade_indices(&colliding),
+            Err(CantDoReason::InvalidTradeIndex)
+        );
+
+        // Finished orders don't count
+        let mut finished = colliding;
+        finished[0].status = Status::Success.to_string();
+        assert!(check_unique_trade_indices(&finished).is_ok());
+
+        // Callers pick the orders of the user, the counterparty using the
+        // same index is not a collision
+        let trade = |buyer: &str, seller: &str, index: i64| Order {
+            buyer_pubkey: Some(buyer.to_string()),
+            seller_pubkey: Some(seller.to_string()),
+            ..order(Some(index), Some(index))
+        };
+        let orders = vec![trade("alice", "bob", 1), trade("bob", "alice", 2)];
+        let alice_orders = orders_of_user(&orders, "alice");
+        assert_eq!(alice_orders.len(), 2);
+        assert!(check_unique_trade_indices(&alice_orders).is_ok());
+        assert!(orders_of_user(&orders, "carol").is_empty());
+
+        // Master pubkeys identify the user too
+        let mut master = trade("trade-key", "bob", 1);
+        master.master_buyer_pubkey = Some("alice".to_string());
+        let orders = vec![trade("alice", "bob", 1), master];
+        assert_eq!(
+            check_unique_trade_indices(&orders_of_user(&orders, "alice")),
+            Err(CantDoReason::InvalidTradeIndex)
+        );
+    }
+
+    #[test]
+    fn test_order_can_pay_invoice() {
+        let order = |status: Status| Order {
+            status: status.to_string(),
+            ..Default::default()
+        };
+        assert!(order(Status::WaitingPayment).can_pay_invoice().is_ok());
+        for status in [
+            Status::Canceled,
+            Status::CanceledByAdmin,
+            Status::CooperativelyCanceled,
+            Status::Expired,
+        ] {
+            assert_eq!(
+                order(status).can_pay_invoice(),
+                Err(CantDoReason::OrderAlreadyCanceled)
+            );
+        }
+        for status in [Status::Pending, Status::Active, Status::Success] {
+            assert_eq!(
+                order(status).can_pay_invoice(),
+                Err(CantDoReason::NotAllowedByStatus)
+            );
+        }
+    }
+
+    #[test]
+    fn test_distinct_fiat_codes() {
+        use crate::order::distinct_fiat_codes;
+
+        let orders: Vec<SmallOrder> = ["usd", "VES", " USD ", "ves", "eur", ""]
+            .into_iter()
+            .map(|code| SmallOrder {
+                fiat_code: code.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        assert_eq!(distinct_fiat_codes(&orders), vec!["EUR", "USD", "VES"]);
+        assert!(distinct_fiat_codes(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_message_stable_signing() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let order = SmallOrder {
+            id: Some(uuid),
+            kind: Some(Kind::Sell),
+            status: Some(Status::Pending),
+            fiat_code: "VES".to_string(),
+            fiat_amount: 100,
+            payment_method: "face to face".to_string(),
+            premium: 1,
+            ..Default::default()
+        };
+        let message = Message::new_order(
+            Some(uuid),
+            Some(1),
+            Some(2),
+            Action::NewOrder,
+            Some(Payload::Order(order)),
+        );
+        let keys = Keys::generate();
+        assert!(message.assert_stable_signing(&keys).is_ok());
+    }
+
+    #[test]
+    fn test_order_try_transition() {
+        use crate::order::allowed_transitions;
+
+        let mut order = Order {
+            status: Status::Pending.to_string(),
+            ..Default::default()
+        };
+        for status in [
+            Status::WaitingPayment,
+            Status::WaitingBuyerInvoice,
+            Status::Active,
+            Status::FiatSent,
+            Status::SettledHoldInvoice,
+            Status::Success,
+        ] {
+            assert!(order.try_transition(status).is_ok());
+            assert_eq!(order.status, status.to_string());
+        }
+        assert!(allowed_transitions(Status::Success).is_empty());
+
+        let mut order = Order {
+            status: Status::Pending.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            order.try_transition(Status::Success),
+            Err(CantDoReason::NotAllowedByStatus)
+        );
+        assert_eq!(
+            order.try_transition(Status::FiatSent),
+            Err(CantDoReason::NotAllowedByStatus)
+        );
+        assert_eq!(order.status, Status::Pending.to_string());
+
+        order.status = Status::Active.to_string();
+        assert!(order.try_transition(Status::Dispute).is_ok());
+        assert_eq!(
+            order.try_transition(Status::Pending),
+            Err(CantDoReason::NotAllowedByStatus)
+        );
+        assert!(order.try_transition(Status::CanceledByAdmin).is_ok());
+        assert!(allowed_transitions(Status::CanceledByAdmin).is_empty());
+    }
+
+    #[test]
+    fn test_allowed_transitions_consistency() {
+        use crate::order::allowed_transitions;
+
+        for status in Status::all() {
+            assert_eq!(
+                status.is_terminal(),
+                allowed_transitions(*status).is_empty(),
+                "{status}"
+            );
+        }
+
+        // Every status can be reached from pending
+        let mut reached = vec![Status::Pending];
+        let mut next = 0;
+        while next < reached.len() {
+            for status in allowed_transitions(reached[next]) {
+                if !reached.contains(status) {
+                    reached.push(*status);
+                }
+            }
+            next += 1;
+        }
+        for status in Status::all() {
+            assert!(reached.contains(status), "{status} is unreachable");
+        }
+
+        // Every status where an action is allowed can move to its outcome
+        let outcomes = [
+            (Action::FiatSent, vec![Status::FiatSent]),
+            (Action::Release, vec![Status::SettledHoldInvoice]),
+            (Action::Dispute, vec![Status::Dispute]),
+            (
+                Action::Cancel,
+                vec![Status::Canceled, Status::CooperativelyCanceled],
+            ),
+        ];
+        for (action, targets) in outcomes {
+            for status in Status::all() {
+                let order = Order {
+                    status: status.to_string(),
+                    ..Default::default()
+                };
+                if order.reason_for_disallowed(&action).is_none() {
+                    assert!(
+                        targets
+                            .iter()
+                            .any(|target| allowed_transitions(*status).contains(target)),
+                        "{action} from {status}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_message_cbor() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let message = Message::new_order(
+            Some(uuid),
+            Some(1),
+            Some(3),
+            Action::PayInvoice,
+            Some(Payload::PaymentRequest(
+                Some(SmallOrder {
+                    id: Some(uuid),
+                    kind: Some(Kind::Sell),
+                    status: Some(Status::WaitingPayment),
+                    amount: 100,
+                    fiat_code: "eur".to_string(),
+                    fiat_amount: 100,
+                    payment_method: "SEPA".to_string(),
+                    premium: 1,
+                    ..Default::default()
+                }),
+                "lnbcrt78510n1pj59wmepp50677g8tffdqa2p8882y0x6newny5vtz0hjuyngdwv226nanv4uzsdqqcqzzsxqyz5vqsp5skn973360gp4yhlpmefwvul5hs58lkkl3u3ujvt57elmp4zugp4q9qyyssqw4nzlr72w28k4waycf27qvgzc9sp79sqlw83j56txltz4va44j7jda23ydcujj9y5k6k0rn5ms84w8wmcmcyk5g3mhpqepf7envhdccp72nz6e".to_string(),
+                None,
+            )),
+        );
+        let cbor = message.as_cbor().unwrap();
+        let json = message.as_json().unwrap();
+        assert!(cbor.len() < json.len());
+
+        let decoded = Message::from_cbor(&cbor).unwrap();
+        assert!(decoded.verify());
+        assert_eq!(decoded.as_json().unwrap(), json);
+        assert_eq!(decoded, message);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_message_cbor_all_variants() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let round_trip = |message: Message| {
+            let decoded = Message::from_cbor(&message.as_cbor().unwrap()).unwrap();
+            assert_eq!(decoded.verify(), message.verify());
+            assert_eq!(decoded.as_json().unwrap(), message.as_json().unwrap());
+            assert_eq!(decoded, message);
+        };
+
+        for action in [
+            Action::NewOrder,
+            Action::TakeSell,
+            Action::TakeBuy,
+            Action::PayInvoice,
+            Action::FiatSent,
+            Action::FiatSentOk,
+            Action::Release,
+            Action::Released,
+            Action::Cancel,
+            Action::Canceled,
+            Action::CooperativeCancelInitiatedByYou,
+            Action::CooperativeCancelInitiatedByPeer,
+            Action::DisputeInitiatedByYou,
+            Action::DisputeInitiatedByPeer,
+            Action::CooperativeCancelAccepted,
+            Action::BuyerInvoiceAccepted,
+            Action::PurchaseCompleted,
+            Action::HoldInvoicePaymentAccepted,
+            Action::HoldInvoicePaymentSettled,
+            Action::HoldInvoicePaymentCanceled,
+            Action::WaitingSellerToPay,
+            Action::WaitingBuyerInvoice,
+            Action::AddInvoice,
+            Action::BuyerTookOrder,
+            Action::Rate,
+            Action::RateUser,
+            Action::RateReceived,
+            Action::CantDo,
+            Action::Dispute,
+            Action::AdminCancel,
+            Action::AdminCanceled,
+            Action::AdminSettle,
+            Action::AdminSettled,
+            Action::AdminAddSolver,
+            Action::AdminTakeDispute,
+            Action::AdminTookDispute,
+            Action::PaymentFailed,
+            Action::InvoiceUpdated,
+            Action::SendDm,
+            Action::TradePubkey,
+            Action::Hello,
+        ] {
+            round_trip(Message::new_order(
+                Some(uuid),
+                Some(1),
+                Some(2),
+                action,
+                None,
+            ));
+        }
+
+        let order = SmallOrder {
+            id: Some(uuid),
+            kind: Some(Kind::Buy),
+            status: Some(Status::Pending),
+            fiat_code: "VES".to_string(),
+            min_amount: Some(10),
+            max_amount: Some(50),
+            payment_method: "face to face".to_string(),
+            buyer_token: Some(123),
+            ..Default::default()
+        };
+        let payloads = [
+            (Action::NewOrder, Payload::Order(order.clone())),
+            (
+                Action::AddInvoice,
+                Payload::PaymentRequest(Some(order), "lnbcrt1".to_string(), Some(1000)),
+            ),
+            (Action::SendDm, Payload::TextMessage("hello".to_string())),
+            (
+                Action::Cancel,
+                Payload::Peer(Peer::new("pubkey".to_string())),
+            ),
+            (Action::RateUser, Payload::RatingUser(5)),
+            (Action::Release, Payload::Amount(1000)),
+            (Action::Dispute, Payload::Dispute(uuid, Some(456))),
+            (
+                Action::CantDo,
+                Payload::CantDo(Some(CantDoReason::NotAllowedByStatus)),
+            ),
+            (Action::CantDo, Payload::CantDo(None)),
+            (
+                Action::Hello,
+                Payload::Capabilities(Capabilities {
+                    cbor: true,
+                    schema_version: 1,
+                    max_protocol_version: 1,
+                }),
+            ),
+        ];
+        for (action, payload) in payloads {
+            round_trip(Message::new_order(
+                Some(uuid),
+                Some(1),
+                Some(2),
+                action.clone(),
+                Some(payload.clone()),
+            ));
+            round_trip(Message::cant_do(Some(uuid), None, Some(payload.clone())));
+            round_trip(Message::new_dm(Some(uuid), None, action, Some(payload)));
+        }
+    }
+
+    #[test]
+    fn test_small_order_invoice_outlives_order() {
+        // Both invoices were created at 1700000000, expiring in one hour and one day
+        let short = "lnbcrt78510n1pj48ugqpp5j0yz2uyjgu5mcwlj4ucfd2hxjw78lumqr6ra9ua4ptdmsx2gxmxssp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygsdq2d4hhxarjduxqrrss9qrsgqtfh3zqntjcdsgun3w73h7wy05tyw3c5scp2nvzx647edjxdcqjwkzmppmzyxcf45hs29ad6zfxpzjak6kcvgttgqwytsmh2n54wvp5gq79dqfv";
+        let long = "lnbcrt78510n1pj48ugqpp5j0yz2uyjgu5mcwlj4ucfd2hxjw78lumqr6ra9ua4ptdmsx2gxmxssp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygsdq2d4hhxarjduxqyz5vq9qrsgqpup4znc9guqqh9gwe9l2va6cxmzm8gplmtyqrcf6t3vvxdpeygt5s07efhlhv76j9w52fgl48ch489eeprh6kas2xmue6mdr7r0kyxcq8f3zaf";
+        let now = 1700000000;
+        let mut order = SmallOrder {
+            buyer_invoice: Some(long.to_string()),
+            expires_at: Some(now + 7200),
+            ..Default::default()
+        };
+        assert!(order.check_invoice_outlives_order(now).is_ok());
+
+        order.buyer_invoice = Some(short.to_string());
+        assert_eq!(
+            order.check_invoice_outlives_order(now),
+            Err(CantDoReason::InvalidInvoice)
+        );
+
+        order.expires_at = None;
+        assert!(order.check_invoice_outlives_order(now).is_ok());
+    }
+
+    #[test]
+    fn test_message_released() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let message = Message::new_released(uuid, Some(1), Some(2));
+        assert!(message.verify());
+        assert_eq!(message.inner_action(), Some(Action::Released));
+
+        let parsed = Message::from_json(&message.as_json().unwrap()).unwrap();
+        assert!(parsed.verify());
+        assert_eq!(parsed.as_json().unwrap(), message.as_json().unwrap());
+
+        let with_payload = Message::new_order(
+            Some(uuid),
+            None,
+            None,
+            Action::Released,
+            Some(Payload::TextMessage("preimage".to_string())),
+        );
+        assert!(!with_payload.verify());
+        let without_id = Message::new_order(None, None, None, Action::Released, None);
+        assert!(!without_id.verify());
+    }
+
+    #[test]
+    fn test_message_expiration() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let kind = MessageKind::builder(Action::FiatSent)
+            .id(uuid)
+            .expires_at(1700000000)
+            .build();
+        let message = Message::Order(kind);
+        let json = message.as_json().unwrap();
+        assert!(json.contains(r#""expires_at":1700000000"#));
+
+        let parsed = Message::from_json(&json).unwrap();
+        assert!(parsed.verify());
+        assert!(parsed.verify_with_time(1699999999));
+        assert!(!parsed.verify_with_time(1700000000));
+
+        // Expiration is signed, changing it breaks the signature
+        let keys = Keys::generate();
+        let sig = parsed.get_inner_message_kind().sign(&keys);
+        let tampered = json.replace("1700000000", "1800000000");
+        assert!(Message::verify_signed(&tampered, keys.public_key(), sig).is_err());
+
+        let no_expiration = Message::new_released(uuid, None, None);
+        assert!(!no_expiration.as_json().unwrap().contains("expires_at"));
+        assert!(no_expiration.verify_with_time(u64::MAX));
+    }
+
+    #[test]
+    fn test_small_order_age_human() {
+        let now = 1700000000;
+        let order = |created_at: Option<i64>| SmallOrder {
+            created_at,
+            ..Default::default()
+        };
+        assert_eq!(order(Some(now - 30)).age_human(now), "just now");
+        assert_eq!(order(Some(now + 30)).age_human(now), "just now");
+        assert_eq!(order(Some(now - 14 * 60)).age_human(now), "14m ago");
+        assert_eq!(order(Some(now - 3 * 3600 - 59)).age_human(now), "3h ago");
+        assert_eq!(order(Some(now - 2 * 86400)).age_human(now), "2d ago");
+        assert_eq!(order(None).age_human(now), "unknown");
+        assert_eq!(order(Some(0)).age_human(now), "unknown");
+    }
+
+    #[test]
+    fn test_small_order_builder() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let fixed = SmallOrder::builder()
+            .id(uuid)
+            .kind(Kind::Sell)
+            .status(Status::Pending)
+            .amount(100)
+            .fiat_code("eur")
+            .fiat_amount(100)
+            .payment_method("SEPA")
+            .premium(1)
+            .created_at(1627371434)
+            .build()
+            .unwrap();
+        let expected = SmallOrder::new(
+            Some(uuid),
+            Some(Kind::Sell),
+            Some(Status::Pending),
+            100,
+            "eur".to_string(),
+            None,
+            None,
+            100,
+            "SEPA".to_string(),
+            1,
+            None,
+            None,
+            None,
+            Some(1627371434),
+            None,
+            None,
+            None,
+        );
+        assert_eq!(fixed, expected);
+
+        let range = SmallOrder::builder()
+            .kind(Kind::Buy)
+            .fiat_code("VES")
+            .range(10, 50)
+            .payment_method("face to face")
+            .build()
+            .unwrap();
+        let expected = SmallOrder::new(
+            None,
+            Some(Kind::Buy),
+            None,
+            0,
+            "VES".to_string(),
+            Some(10),
+            Some(50),
+            0,
+            "face to face".to_string(),
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(range, expected);
+
+        let inverted = SmallOrder::builder().fiat_code("VES").range(50, 10).build();
+        assert_eq!(inverted, Err(CantDoReason::OutOfRangeFiatAmount));
+        let negative = SmallOrder::builder()
+            .fiat_code("VES")
+            .fiat_amount(100)
+            .amount(-1)
+            .build();
+        assert_eq!(negative, Err(CantDoReason::InvalidAmount));
+        let no_fiat_code = SmallOrder::builder().fiat_amount(100).build();
+        assert_eq!(no_fiat_code, Err(CantDoReason::InvalidFiatCurrency));
+    }
+
+    #[test]
+    fn test_order_effective_amount() {
+        let mut order = Order {
+            fiat_amount: 100,
+            premium: 5,
+            ..Default::default()
+        };
+        assert_eq!(order.effective_amount(Some(1000.0)), Ok(105000));
+        order.premium = -3;
+        assert_eq!(order.effective_amount(Some(1000.0)), Ok(97000));
+        assert_eq!(
+            order.effective_amount(None),
+            Err(CantDoReason::InvalidAmount)
+        );
+
+        order.amount = 5000;
+        assert_eq!(order.effective_amount(None), Ok(5000));
+
+        // A published range order has no fiat amount and can't be priced yet
+        let mut range = Order {
+            kind: Kind::Sell.to_string(),
+            status: Status::Pending.to_string(),
+            min_amount: Some(10),
+            max_amount: Some(50),
+            premium: 5,
+            ..Default::default()
+        };
+        assert!(range.as_new_order().check_fiat_amount().is_ok());
+        assert_eq!(
+            range.effective_amount(Some(1000.0)),
+            Err(CantDoReason::InvalidAmount)
+        );
+
+        // Once taken the fiat amount is the one chosen by the taker
+        range.fiat_amount = 20;
+        assert_eq!(range.effective_amount(Some(1000.0)), Ok(21000));
+        range.fiat_amount = 50;
+        assert_eq!(range.effective_amount(Some(1000.0)), Ok(52500));
+        range.fiat_amount = 60;
+        assert_eq!(
+            range.effective_amount(Some(1000.0)),
+            Err(CantDoReason::OutOfRangeFiatAmount)
+        );
+    }
+
+    #[test]
+    fn test_message_authorize_admin() {
+        let admin = User {
+            is_admin: 1,
+            ..Default::default()
+        };
+        let solver = User {
+            is_solver: 1,
+            ..Default::default()
+        };
+        let user = User::default();
+        let kind = |action: Action| MessageKind::new(None, None, None, action, None);
+
+        let add_solver = kind(Action::AdminAddSolver);
+        assert!(add_solver.authorize_admin(&admin).is_ok());
+        assert_eq!(
+            add_solver.authorize_admin(&solver),
+            Err(CantDoReason::Unauthorized)
+        );
+        assert_eq!(
+            add_solver.authorize_admin(&user),
+            Err(CantDoReason::Unauthorized)
+        );
+
+        for action in [
+            Action::AdminCancel,
+            Action::AdminSettle,
+            Action::AdminTakeDispute,
+        ] {
+            let message = kind(action);
+            assert!(message.authorize_admin(&admin).is_ok());
+            assert!(message.authorize_admin(&solver).is_ok());
+            assert_eq!(
+                message.authorize_admin(&user),
+                Err(CantDoReason::Unauthorized)
+            );
+        }
+
+        let banned = User {
+            is_banned: 1,
+            ..solver
+        };
+        assert_eq!(
+            kind(Action::AdminSettle).authorize_admin(&banned),
+            Err(CantDoReason::Unauthorized)
+        );
+        assert!(kind(Action::FiatSent).authorize_admin(&user).is_ok());
+    }
+
+    #[test]
+    fn test_small_order_fiat_amount_display() {
+        let order = |fiat_code: &str, fiat_amount: i64| SmallOrder {
+            fiat_code: fiat_code.to_string(),
+            fiat_amount,
+            ..Default::default()
+        };
+        assert_eq!(order("USD", 100).fiat_amount_display(), "1.00");
+        assert_eq!(order("usd", 12345).fiat_amount_display(), "123.45");
+        assert_eq!(order("EUR", 5).fiat_amount_display(), "0.05");
+        assert_eq!(order("JPY", 100).fiat_amount_display(), "100");
+        assert_eq!(order("KWD", 1500).fiat_amount_display(), "1.500");
+    }
+
+    #[test]
+    fn test_enum_string_round_trip() {
+        use crate::dispute::Status as DisputeStatus;
+        use std::str::FromStr;
+
+        for status in Status::all() {
+            assert_eq!(Status::from_str(&status.to_string()), Ok(*status));
+        }
+        for kind in Kind::all() {
+            assert_eq!(Kind::from_str(&kind.to_string()), Ok(*kind));
+        }
+        for status in DisputeStatus::all() {
+            assert_eq!(
+                DisputeStatus::from_str(&status.to_string()),
+                Ok(status.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_cant_do_reason_code() {
+        let codes: Vec<u16> = (1000..6000)
+            .filter(|code| CantDoReason::from_code(*code).is_some())
+            .collect();
+        // Every variant has a code
+        assert_eq!(codes.len(), 22);
+        for code in codes {
+            let reason = CantDoReason::from_code(code).unwrap();
+            assert_eq!(reason.code(), code);
+            assert_eq!(CantDoReason::from_code(reason.code()), Some(reason));
+        }
+        assert_eq!(CantDoReason::InvalidSignature.code(), 1001);
+        assert_eq!(CantDoReason::OutOfRangeFiatAmount.code(), 2003);
+        assert_eq!(CantDoReason::from_code(0), None);
+
+        // The wire format is still the reason name, clients get the code
+        // with CantDoReason::code
+        let cant_do = Message::cant_do(
+            None,
+            None,
+            Some(Payload::CantDo(Some(CantDoReason::NotFound))),
+        );
+        let json = cant_do.as_json().unwrap();
+        assert!(json.contains(r#""payload":{"cant_do":"not_found"}"#));
+        let message = Message::from_json(&json).unwrap();
+        let Some(Payload::CantDo(Some(reason))) = &message.get_inner_message_kind().payload else {
+            panic!("Expected a cant do payload");
+        };
+        assert_eq!(reason.code(), 4001);
+    }
+
+    #[test]
+    fn test_small_order_is_duplicate_of() {
+        let order = SmallOrder {
+            id: Some(uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23")),
+            kind: Some(Kind::Sell),
+            fiat_code: "VES".to_string(),
+            fiat_amount: 100,
+            payment_method: "face to face".to_string(),
+            created_at: Some(1700000000),
+            ..Default::default()
+        };
+
+        // Same id, the content may have changed between relays
+        let updated = SmallOrder {
+            status: Some(Status::Canceled),
+            fiat_amount: 200,
+            ..order.clone()
+        };
+        assert!(order.is_duplicate_of(&updated));
+
+        let other_id = SmallOrder {
+            id: Some(uuid!("308e1272-d5f4-47e6-bd97-3504baea9c24")),
+            ..order.clone()
+        };
+        assert!(!order.is_duplicate_of(&other_id));
+
+        let no_id = SmallOrder {
+            id: None,
+            fiat_code: "ves".to_string(),
+            ..order.clone()
+        };
+        assert!(order.is_duplicate_of(&no_id));
+        assert!(other_id.is_duplicate_of(&no_id));
+
+        let different = SmallOrder {
+            fiat_amount: 200,
+            ..no_id.clone()
+        };
+        assert!(!no_id.is_duplicate_of(&different));
+    }
+
+    #[test]
+    fn test_dispute_participant_view() {
+        let mut dispute = Dispute::new(uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23"));
+        dispute.solver_pubkey = Some("solver".to_string());
+        dispute.buyer_token = Some(123);
+        dispute.seller_token = Some(456);
+
+        let buyer_view = dispute.participant_view(true);
+        assert_eq!(buyer_view.token, Some(123));
+        assert_eq!(buyer_view.order_id, dispute.order_id);
+        assert_eq!(buyer_view.status, dispute.status);
+        let json = serde_json::to_string(&buyer_view).unwrap();
+        assert!(!json.contains("seller_token"));
+        assert!(!json.contains("solver"));
+
+        assert_eq!(dispute.participant_view(false).token, Some(456));
+    }
+
+    #[cfg(feature = "sqlx")]
+    #[tokio::test]
+    async fn test_order_read_by_status() {
+        let pool = sqlite_pool().await;
+        create_orders_table(&pool).await;
+        for created_at in 1..=5 {
+            insert_order(&pool, Status::Pending, "alice", created_at).await;
+        }
+        insert_order(&pool, Status::Active, "alice", 6).await;
+
+        let mut pages = Vec::new();
+        for offset in [0, 2, 4] {
+            let page = Order::read_by_status(&pool, Status::Pending, 2, offset)
+                .await
+                .unwrap();
+            pages.push(page.iter().map(|o| o.created_at).collect::<Vec<_>>());
+        }
+        assert_eq!(pages, vec![vec![5, 4], vec![3, 2], vec![1]]);
+
+        let active = Order::read_by_status(&pool, Status::Active, 2, 0)
+            .await
+            .unwrap();
+        assert_eq!(active.len(), 1);
+    }
+
+    #[test]
+    fn test_message_check_size() {
+        let uuid = uuid!("308e1272-d5f4-47e6-bd97-3504baea9c23");
+        let small = Message::new_released(uuid, None, None);
+        assert_eq!(small.estimated_size(), small.as_json().unwrap().len());
+        assert!(small.check_size(64 * 1024).is_ok());
+
+        let huge = Message::new_dm(
+            None,
+            None,
+            Action::SendDm,
+            Some(Payload::TextMessage("a".repeat(100_000))),
+        );
+        assert!(huge.estimated_size() > 100_000);
+        assert!(huge.check_size(64 * 1024).is_err());
+    }
 }
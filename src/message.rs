@@ -1,17 +1,19 @@
-use crate::order::SmallOrder;
+use crate::order::{normalize_payment_request, Kind, Order, Role, SmallOrder, Status};
+use crate::user::User;
 use crate::PROTOCOL_VER;
 use anyhow::{Ok, Result};
 use bitcoin::hashes::sha256::Hash as Sha256Hash;
 use bitcoin::hashes::Hash;
 use bitcoin::key::Secp256k1;
 use bitcoin::secp256k1::Message as BitcoinMessage;
+use bitcoin::secp256k1::VerifyOnly;
 use nostr_sdk::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use uuid::Uuid;
 
 /// One party of the trade
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Peer {
     pub pubkey: String,
 }
@@ -28,6 +30,35 @@ impl Peer {
     pub fn as_json(&self) -> Result<String> {
         Ok(serde_json::to_string(&self)?)
     }
+
+    /// Get the nostr `p` tag referencing this peer
+    pub fn to_p_tag(&self) -> Result<Tag, CantDoReason> {
+        let pubkey = PublicKey::parse(&self.pubkey).map_err(|_| CantDoReason::InvalidPubkey)?;
+
+        std::result::Result::Ok(Tag::public_key(pubkey))
+    }
+
+    /// New peer from a nostr `p` tag
+    pub fn from_p_tag(tag: &Tag) -> Result<Self, CantDoReason> {
+        let t = tag.clone().to_vec();
+        match (t.first().map(String::as_str), t.get(1)) {
+            (Some("p"), Some(pubkey)) => {
+                let pubkey = PublicKey::parse(pubkey).map_err(|_| CantDoReason::InvalidPubkey)?;
+                std::result::Result::Ok(Self::new(pubkey.to_hex()))
+            }
+            _ => Err(CantDoReason::InvalidPeer),
+        }
+    }
+}
+
+/// Protocol features supported by a client or by Mostro
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// CBOR encoded messages are supported
+    pub cbor: bool,
+    pub schema_version: u8,
+    /// Highest message version supported
+    pub max_protocol_version: u8,
 }
 
 /// Action is used to identify each message between Mostro and users
@@ -74,6 +105,28 @@ pub enum Action {
     InvoiceUpdated,
     SendDm,
     TradePubkey,
+    Hello,
+}
+
+impl Action {
+    /// Actions that advance the user's trade index must carry it,
+    /// it is used to prevent replay of order creation and taking
+    pub fn requires_trade_index(&self) -> bool {
+        matches!(self, Action::NewOrder | Action::TakeSell | Action::TakeBuy)
+    }
+
+    /// Actions that end the trade flow, after them the conversation is over
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            Action::Released
+                | Action::PurchaseCompleted
+                | Action::Canceled
+                | Action::CooperativeCancelAccepted
+                | Action::AdminCanceled
+                | Action::AdminSettled
+        )
+    }
 }
 
 impl fmt::Display for Action {
@@ -83,7 +136,7 @@ impl fmt::Display for Action {
 }
 
 /// Use this Message to establish communication between users and Mostro
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub enum Message {
     Order(MessageKind),
@@ -138,6 +191,40 @@ impl Message {
         Self::Dm(kind)
     }
 
+    /// New cancel message, the initiator is included as peer when known
+    pub fn new_cancel(
+        id: Uuid,
+        request_id: Option<u64>,
+        trade_index: Option<i64>,
+        initiator_pubkey: Option<String>,
+    ) -> Self {
+        let payload = initiator_pubkey.map(|pubkey| Payload::Peer(Peer::new(pubkey)));
+        let kind = MessageKind::new(Some(id), request_id, trade_index, Action::Cancel, payload);
+
+        Self::Order(kind)
+    }
+
+    /// New message confirming the seller released the sats, no payload is
+    /// attached so the preimage is never sent
+    pub fn new_released(id: Uuid, request_id: Option<u64>, trade_index: Option<i64>) -> Self {
+        let kind = MessageKind::new(Some(id), request_id, trade_index, Action::Released, None);
+
+        Self::Order(kind)
+    }
+
+    /// New handshake message advertising the supported capabilities
+    pub fn hello(request_id: Option<u64>, capabilities: Capabilities) -> Self {
+        let kind = MessageKind::new(
+            None,
+            request_id,
+            None,
+            Action::Hello,
+            Some(Payload::Capabilities(capabilities)),
+        );
+
+        Self::Order(kind)
+    }
+
     /// Get message from json string
     pub fn from_json(json: &str) -> Result<Self> {
         Ok(serde_json::from_str(json)?)
@@ -148,6 +235,39 @@ impl Message {
         Ok(serde_json::to_string(&self)?)
     }
 
+    /// Get the size in bytes of the message serialized as json, 0 if it
+    /// can't be serialized, use `check_size` to catch that error
+    pub fn estimated_size(&self) -> usize {
+        self.as_json().map(|json| json.len()).unwrap_or_default()
+    }
+
+    /// Check that the message serializes and fits in the given size, relays
+    /// reject oversized events
+    pub fn check_size(&self, max_bytes: usize) -> Result<()> {
+        let size = self.as_json()?.len();
+        if size > max_bytes {
+            return Err(anyhow::anyhow!(
+                "Message too large: {size} bytes, max {max_bytes}"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Get message as cbor bytes, a compact alternative to json
+    #[cfg(feature = "cbor")]
+    pub fn as_cbor(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Get message from cbor bytes
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self> {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+
     // Get inner message kind
     pub fn get_inner_message_kind(&self) -> &MessageKind {
         match self {
@@ -170,6 +290,116 @@ impl Message {
         }
     }
 
+    /// Parse a message from json, check it is valid and that the signature of
+    /// its inner message matches the pubkey, the error tells which check failed
+    pub fn verify_signed(json: &str, pubkey: PublicKey, sig: Signature) -> Result<Self> {
+        let message = Self::from_json(json)?;
+        if !message.verify() {
+            return Err(anyhow::anyhow!("Message is not valid for its action"));
+        }
+        if !message
+            .get_inner_message_kind()
+            .verify_signature(pubkey, sig)
+        {
+            return Err(anyhow::anyhow!("Message signature is not valid"));
+        }
+
+        Ok(message)
+    }
+
+    /// Check that the message serializes to the same json after a round trip
+    /// and that its signature still verifies on the parsed message
+    pub fn assert_stable_signing(&self, keys: &Keys) -> Result<()> {
+        let sig = self.get_inner_message_kind().sign(keys);
+        let json = self.as_json()?;
+        let parsed = Self::from_json(&json)?;
+        let reserialized = parsed.as_json()?;
+        if reserialized != json {
+            return Err(anyhow::anyhow!(
+                "Message json changed after round trip: {json} != {reserialized}"
+            ));
+        }
+        if !parsed
+            .get_inner_message_kind()
+            .verify_signature(keys.public_key(), sig)
+        {
+            return Err(anyhow::anyhow!(
+                "Message signature does not verify after round trip"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Get a copy of the message without payload, useful to log messages
+    /// without exposing their content
+    pub fn header_only(&self) -> Message {
+        let kind = self.get_inner_message_kind();
+        self.with_kind(MessageKind {
+            version: kind.version,
+            request_id: kind.request_id,
+            trade_index: kind.trade_index,
+            id: kind.id,
+            action: kind.action.clone(),
+            payload: None,
+            expires_at: kind.expires_at,
+        })
+    }
+
+    /// Get the message as json with invoices, pubkeys and dispute tokens
+    /// masked, safe to be written in debug logs
+    pub fn debug_safe(&self) -> String {
+        const REDACTED: &str = "[redacted]";
+        let redact = |field: &Option<String>| field.as_ref().map(|_| REDACTED.to_string());
+        let redact_order = |order: &SmallOrder| SmallOrder {
+            buyer_trade_pubkey: redact(&order.buyer_trade_pubkey),
+            seller_trade_pubkey: redact(&order.seller_trade_pubkey),
+            buyer_invoice: redact(&order.buyer_invoice),
+            buyer_token: None,
+            seller_token: None,
+            ..order.clone()
+        };
+        let kind = self.get_inner_message_kind();
+        let payload = kind.payload.as_ref().map(|payload| match payload {
+            Payload::Order(order) => Payload::Order(redact_order(order)),
+            Payload::PaymentRequest(order, _, amount) => Payload::PaymentRequest(
+                order.as_ref().map(redact_order),
+                REDACTED.to_string(),
+                *amount,
+            ),
+            Payload::Dispute(id, _) => Payload::Dispute(*id, None),
+            Payload::Peer(_) => Payload::Peer(Peer::new(REDACTED.to_string())),
+            payload => payload.clone(),
+        });
+        let message = self.with_kind(MessageKind {
+            version: kind.version,
+            request_id: kind.request_id,
+            trade_index: kind.trade_index,
+            id: kind.id,
+            action: kind.action.clone(),
+            payload,
+            expires_at: kind.expires_at,
+        });
+
+        message.as_json().unwrap_or_default()
+    }
+
+    // Wrap a message kind in the same variant as this message
+    fn with_kind(&self, kind: MessageKind) -> Message {
+        match self {
+            Message::Order(_) => Message::Order(kind),
+            Message::Dispute(_) => Message::Dispute(kind),
+            Message::CantDo(_) => Message::CantDo(kind),
+            Message::Rate(_) => Message::Rate(kind),
+            Message::Dm(_) => Message::Dm(kind),
+        }
+    }
+
+    /// Check if the message ends the trade flow
+    pub fn is_terminal(&self) -> bool {
+        self.get_inner_message_kind().action.is_terminal()
+    }
+
     /// Verify if is valid the inner message
     pub fn verify(&self) -> bool {
         match self {
@@ -180,10 +410,15 @@ impl Message {
             | Message::Dm(m) => m.verify(),
         }
     }
+
+    /// Verify the inner message and that it is not expired at the given time
+    pub fn verify_with_time(&self, now: u64) -> bool {
+        self.verify() && !self.get_inner_message_kind().is_expired(now)
+    }
 }
 
 /// Use this Message to establish communication between users and Mostro
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct MessageKind {
     /// Message version
     pub version: u8,
@@ -198,6 +433,10 @@ pub struct MessageKind {
     pub action: Action,
     /// Payload of the Message
     pub payload: Option<Payload>,
+    /// Unix timestamp after which the message must be ignored, it is part
+    /// of the signed content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
 }
 
 type Amount = i64;
@@ -224,6 +463,8 @@ pub enum CantDoReason {
     InvalidTextMessage,
     /// The order kind is invalid
     InvalidOrderKind,
+    /// The fiat currency code is invalid
+    InvalidFiatCurrency,
     /// The order status is invalid
     InvalidOrderStatus,
     /// Invalid pubkey
@@ -246,10 +487,73 @@ pub enum CantDoReason {
     IsNotYourDispute,
     /// Generic not found
     NotFound,
+    /// The user has no permission for the requested action
+    Unauthorized,
+}
+
+impl CantDoReason {
+    /// Get the stable numeric code of the reason, grouped by thousands:
+    /// 1xxx invalid input, 2xxx order state, 3xxx permissions, 4xxx missing
+    /// data and 5xxx server side failures
+    pub fn code(&self) -> u16 {
+        match self {
+            CantDoReason::InvalidSignature => 1001,
+            CantDoReason::InvalidTradeIndex => 1002,
+            CantDoReason::InvalidAmount => 1003,
+            CantDoReason::InvalidInvoice => 1004,
+            CantDoReason::InvalidPaymentRequest => 1005,
+            CantDoReason::InvalidPeer => 1006,
+            CantDoReason::InvalidRating => 1007,
+            CantDoReason::InvalidTextMessage => 1008,
+            CantDoReason::InvalidOrderKind => 1009,
+            CantDoReason::InvalidFiatCurrency => 1010,
+            CantDoReason::InvalidOrderStatus => 1011,
+            CantDoReason::InvalidPubkey => 1012,
+            CantDoReason::InvalidParameters => 1013,
+            CantDoReason::OrderAlreadyCanceled => 2001,
+            CantDoReason::NotAllowedByStatus => 2002,
+            CantDoReason::OutOfRangeFiatAmount => 2003,
+            CantDoReason::OutOfRangeSatsAmount => 2004,
+            CantDoReason::IsNotYourOrder => 3001,
+            CantDoReason::IsNotYourDispute => 3002,
+            CantDoReason::Unauthorized => 3003,
+            CantDoReason::NotFound => 4001,
+            CantDoReason::CantCreateUser => 5001,
+        }
+    }
+
+    /// Get the reason of a numeric code, None if the code is unknown
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            1001 => Some(CantDoReason::InvalidSignature),
+            1002 => Some(CantDoReason::InvalidTradeIndex),
+            1003 => Some(CantDoReason::InvalidAmount),
+            1004 => Some(CantDoReason::InvalidInvoice),
+            1005 => Some(CantDoReason::InvalidPaymentRequest),
+            1006 => Some(CantDoReason::InvalidPeer),
+            1007 => Some(CantDoReason::InvalidRating),
+            1008 => Some(CantDoReason::InvalidTextMessage),
+            1009 => Some(CantDoReason::InvalidOrderKind),
+            1010 => Some(CantDoReason::InvalidFiatCurrency),
+            1011 => Some(CantDoReason::InvalidOrderStatus),
+            1012 => Some(CantDoReason::InvalidPubkey),
+            1013 => Some(CantDoReason::InvalidParameters),
+            2001 => Some(CantDoReason::OrderAlreadyCanceled),
+            2002 => Some(CantDoReason::NotAllowedByStatus),
+            2003 => Some(CantDoReason::OutOfRangeFiatAmount),
+            2004 => Some(CantDoReason::OutOfRangeSatsAmount),
+            3001 => Some(CantDoReason::IsNotYourOrder),
+            3002 => Some(CantDoReason::IsNotYourDispute),
+            3003 => Some(CantDoReason::Unauthorized),
+            4001 => Some(CantDoReason::NotFound),
+            5001 => Some(CantDoReason::CantCreateUser),
+            _ => None,
+        }
+    }
 }
 
 /// Message payload
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum Payload {
     Order(SmallOrder),
@@ -260,6 +564,24 @@ pub enum Payload {
     Amount(Amount),
     Dispute(Uuid, Option<u16>),
     CantDo(Option<CantDoReason>),
+    Capabilities(Capabilities),
+}
+
+impl Payload {
+    /// Get the key used for this payload in the serialized message
+    pub fn tag_name(&self) -> &'static str {
+        match self {
+            Payload::Order(_) => "order",
+            Payload::PaymentRequest(_, _, _) => "payment_request",
+            Payload::TextMessage(_) => "text_message",
+            Payload::Peer(_) => "peer",
+            Payload::RatingUser(_) => "rating_user",
+            Payload::Amount(_) => "amount",
+            Payload::Dispute(_, _) => "dispute",
+            Payload::CantDo(_) => "cant_do",
+            Payload::Capabilities(_) => "capabilities",
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -279,6 +601,13 @@ impl MessageKind {
             id,
             action,
             payload,
+            expires_at: None,
+        }
+    }
+    /// Start building a message for the action, every other field is empty
+    pub fn builder(action: Action) -> MessageKindBuilder {
+        MessageKindBuilder {
+            kind: Self::new(None, None, None, action, None),
         }
     }
     /// Get message from json string
@@ -295,8 +624,16 @@ impl MessageKind {
         self.action.clone()
     }
 
+    /// Check if the message expired, messages without expiration never do
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
     /// Verify if is valid message
     pub fn verify(&self) -> bool {
+        if self.action.requires_trade_index() && self.trade_index.is_none() {
+            return false;
+        }
         match &self.action {
             Action::NewOrder => matches!(&self.payload, Some(Payload::Order(_))),
             Action::PayInvoice | Action::AddInvoice => {
@@ -310,7 +647,6 @@ impl MessageKind {
             | Action::FiatSent
             | Action::FiatSentOk
             | Action::Release
-            | Action::Released
             | Action::Dispute
             | Action::AdminCancel
             | Action::AdminCanceled
@@ -333,7 +669,6 @@ impl MessageKind {
             | Action::CooperativeCancelInitiatedByYou
             | Action::CooperativeCancelInitiatedByPeer
             | Action::CooperativeCancelAccepted
-            | Action::Cancel
             | Action::PaymentFailed
             | Action::TradePubkey
             | Action::InvoiceUpdated
@@ -345,15 +680,62 @@ impl MessageKind {
                 }
                 true
             }
+            Action::Released => self.id.is_some() && self.payload.is_none(),
+            Action::Cancel => {
+                if self.id.is_none() {
+                    return false;
+                }
+                matches!(&self.payload, None | Some(Payload::Peer(_)))
+            }
             Action::RateUser => {
                 matches!(&self.payload, Some(Payload::RatingUser(_)))
             }
             Action::CantDo => {
                 matches!(&self.payload, Some(Payload::CantDo(_)))
             }
+            Action::Hello => {
+                matches!(&self.payload, Some(Payload::Capabilities(_)))
+            }
+        }
+    }
+
+    /// Check that the rater was a party of the order and the order can be rated
+    pub fn validate_rating_target(
+        &self,
+        order: &Order,
+        rater_pubkey: &str,
+    ) -> Result<(), CantDoReason> {
+        let is_party = [&order.buyer_pubkey, &order.seller_pubkey]
+            .iter()
+            .any(|pubkey| pubkey.as_deref() == Some(rater_pubkey));
+        if !is_party {
+            return Err(CantDoReason::IsNotYourOrder);
+        }
+        match order.reason_for_disallowed(&Action::RateUser) {
+            Some(reason) => Err(reason),
+            None => std::result::Result::Ok(()),
         }
     }
 
+    /// Check that the user can request an admin action, adding solvers is
+    /// reserved to admins while the other admin actions are also allowed to
+    /// solvers. Non admin actions are always authorized
+    pub fn authorize_admin(&self, user: &User) -> Result<(), CantDoReason> {
+        let (is_admin, is_solver) = (user.is_admin != 0, user.is_solver != 0);
+        let authorized = match self.action {
+            Action::AdminAddSolver => is_admin,
+            Action::AdminCancel | Action::AdminSettle | Action::AdminTakeDispute => {
+                is_admin || is_solver
+            }
+            _ => return std::result::Result::Ok(()),
+        };
+        if !authorized || user.is_banned != 0 {
+            return Err(CantDoReason::Unauthorized);
+        }
+
+        std::result::Result::Ok(())
+    }
+
     pub fn get_order(&self) -> Option<&SmallOrder> {
         if self.action != Action::NewOrder {
             return None;
@@ -378,6 +760,40 @@ impl MessageKind {
         }
     }
 
+    /// Normalize the invoices carried by the payload before they are stored,
+    /// the `lightning:` prefix and whitespace are removed and invoices are
+    /// lowercased, see `order::normalize_invoice`
+    pub fn normalize_invoices(&mut self) -> Result<(), CantDoReason> {
+        let order = match &mut self.payload {
+            Some(Payload::PaymentRequest(order, invoice, _)) => {
+                *invoice = normalize_payment_request(invoice)?;
+                order.as_mut()
+            }
+            Some(Payload::Order(order)) => Some(order),
+            _ => None,
+        };
+        if let Some(order) = order {
+            if let Some(invoice) = order.buyer_invoice.take() {
+                order.set_buyer_invoice(&invoice)?;
+            }
+        }
+
+        std::result::Result::Ok(())
+    }
+
+    /// Check that the explicit amount of a payment request agrees with the
+    /// amount of the order it carries, nothing to check if one is missing
+    pub fn check_payment_request_amount(&self) -> Result<(), CantDoReason> {
+        match &self.payload {
+            Some(Payload::PaymentRequest(Some(order), _, Some(amount)))
+                if order.amount != *amount =>
+            {
+                Err(CantDoReason::InvalidAmount)
+            }
+            _ => std::result::Result::Ok(()),
+        }
+    }
+
     pub fn get_amount(&self) -> Option<Amount> {
         if self.action != Action::TakeSell && self.action != Action::TakeBuy {
             return None;
@@ -400,6 +816,17 @@ impl MessageKind {
         (false, 0)
     }
 
+    /// Check that the trade index, when present, is not negative nor above
+    /// the highest reasonable value
+    pub fn check_trade_index_sane(&self, max_reasonable: i64) -> Result<(), CantDoReason> {
+        match self.trade_index {
+            Some(index) if !(0..=max_reasonable).contains(&index) => {
+                Err(CantDoReason::InvalidTradeIndex)
+            }
+            _ => std::result::Result::Ok(()),
+        }
+    }
+
     pub fn sign(&self, keys: &Keys) -> Signature {
         let message = self.as_json().unwrap();
         let hash: Sha256Hash = Sha256Hash::hash(message.as_bytes());
@@ -421,3 +848,141 @@ impl MessageKind {
         pubkey.verify(&secp, &message, &sig).is_ok()
     }
 }
+
+/// Builder of messages where only the needed fields are set
+#[derive(Debug)]
+pub struct MessageKindBuilder {
+    kind: MessageKind,
+}
+
+impl MessageKindBuilder {
+    pub fn id(mut self, id: Uuid) -> Self {
+        self.kind.id = Some(id);
+        self
+    }
+
+    pub fn request_id(mut self, request_id: u64) -> Self {
+        self.kind.request_id = Some(request_id);
+        self
+    }
+
+    pub fn trade_index(mut self, trade_index: i64) -> Self {
+        self.kind.trade_index = Some(trade_index);
+        self
+    }
+
+    pub fn payload(mut self, payload: Payload) -> Self {
+        self.kind.payload = Some(payload);
+        self
+    }
+
+    pub fn expires_at(mut self, expires_at: u64) -> Self {
+        self.kind.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn build(self) -> MessageKind {
+        self.kind
+    }
+}
+
+/// Verification context to reuse when checking many message signatures
+#[derive(Debug)]
+pub struct VerifierContext {
+    secp: Secp256k1<VerifyOnly>,
+}
+
+impl Default for VerifierContext {
+    fn default() -> Self {
+        Self {
+            secp: Secp256k1::verification_only(),
+        }
+    }
+}
+
+impl VerifierContext {
+    /// Verify the signature of a serialized message
+    pub fn verify(&self, message: &str, pubkey: PublicKey, sig: Signature) -> bool {
+        let hash: Sha256Hash = Sha256Hash::hash(message.as_bytes());
+        let message: BitcoinMessage = BitcoinMessage::from_digest(hash.to_byte_array());
+        pubkey.verify(&self.secp, &message, &sig).is_ok()
+    }
+}
+
+/// Error returned by `validate_index_sequence`, with the position of the
+/// offending message in the batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexSequenceError {
+    pub position: usize,
+    pub reason: CantDoReason,
+}
+
+/// Check that the trade indexes of a batch of messages are strictly increasing,
+/// a message without trade index or out of sequence makes the batch invalid
+pub fn validate_index_sequence(msgs: &[&MessageKind]) -> Result<(), IndexSequenceError> {
+    let mut last = None;
+    for (position, msg) in msgs.iter().enumerate() {
+        let invalid = IndexSequenceError {
+            position,
+            reason: CantDoReason::InvalidTradeIndex,
+        };
+        let index = msg.trade_index.ok_or(invalid.clone())?;
+        if last.is_some_and(|last| index <= last) {
+            return Err(invalid);
+        }
+        last = Some(index);
+    }
+
+    std::result::Result::Ok(())
+}
+
+/// Get the derivation path of the trade key with the given index, trade keys
+/// are derived from the user's master key as `m/44'/1237'/38383'/0/{trade_index}`
+pub fn trade_key_path(trade_index: i64) -> Result<String, CantDoReason> {
+    // Non hardened child indexes must be lower than 2^31
+    if !(0..1 << 31).contains(&trade_index) {
+        return Err(CantDoReason::InvalidTradeIndex);
+    }
+
+    std::result::Result::Ok(format!("m/44'/1237'/38383'/0/{trade_index}"))
+}
+
+/// Get the actions a party of the trade is expected to send next, given the
+/// status of the order. Derived from `Order::reason_for_disallowed` so both
+/// always agree; the seller pays the hold invoice outside of the protocol
+/// messages
+pub fn expected_action(order_status: Status, role: Role) -> Vec<Action> {
+    let (kind, candidates): (Kind, &[Action]) = match role {
+        Role::Buyer => (
+            Kind::Sell,
+            &[
+                Action::TakeSell,
+                Action::AddInvoice,
+                Action::FiatSent,
+                Action::Cancel,
+                Action::Dispute,
+                Action::RateUser,
+            ],
+        ),
+        Role::Seller => (
+            Kind::Buy,
+            &[
+                Action::TakeBuy,
+                Action::Release,
+                Action::Cancel,
+                Action::Dispute,
+                Action::RateUser,
+            ],
+        ),
+    };
+    let order = Order {
+        kind: kind.to_string(),
+        status: order_status.to_string(),
+        ..Default::default()
+    };
+    candidates
+        .iter()
+        .filter(|action| order.reason_for_disallowed(action).is_none())
+        .cloned()
+        .collect()
+}
@@ -1,16 +1,25 @@
+use crate::message::{Action, CantDoReason};
 use anyhow::{Ok, Result};
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::FromHex;
+#[cfg(feature = "sqlx")]
+use futures::{Stream, StreamExt};
+use lightning_invoice::Bolt11Invoice;
+use nostr_sdk::prelude::{Event, PublicKey, Tags, ToBech32};
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "sqlx")]
-use sqlx::FromRow;
+use sqlx::{FromRow, SqlitePool};
 #[cfg(feature = "sqlx")]
 use sqlx_crud::SqlxCrud;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::{fmt::Display, str::FromStr};
 use uuid::Uuid;
 use wasm_bindgen::prelude::*;
 
 /// Orders can be only Buy or Sell
 #[wasm_bindgen]
-#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 pub enum Kind {
     Buy,
@@ -29,6 +38,18 @@ impl FromStr for Kind {
     }
 }
 
+/// Deserialize through FromStr so the kind is case insensitive
+impl<'de> Deserialize<'de> for Kind {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let kind = String::deserialize(deserializer)?;
+        Kind::from_str(&kind)
+            .map_err(|_| serde::de::Error::custom(format!("unknown order kind: {kind}")))
+    }
+}
+
 impl Display for Kind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -38,10 +59,42 @@ impl Display for Kind {
     }
 }
 
-/// Each status that an order can have
+impl Kind {
+    /// Every kind of order
+    pub fn all() -> &'static [Kind] {
+        &[Kind::Buy, Kind::Sell]
+    }
+
+    /// Get the opposite kind, a sell order is a buy from the taker's point of view
+    pub fn counterpart(&self) -> Kind {
+        match self {
+            Kind::Buy => Kind::Sell,
+            Kind::Sell => Kind::Buy,
+        }
+    }
+
+    /// Get the role of the user taking an order of this kind
+    pub fn taker_role(&self) -> Role {
+        match self {
+            Kind::Buy => Role::Seller,
+            Kind::Sell => Role::Buyer,
+        }
+    }
+}
+
+/// Role of a user in a trade
 #[wasm_bindgen]
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Buyer,
+    Seller,
+}
+
+/// Each status that an order can have
+#[wasm_bindgen]
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
 pub enum Status {
     Active,
     Canceled,
@@ -83,8 +136,9 @@ impl Display for Status {
 impl FromStr for Status {
     type Err = ();
 
+    /// Accepts both kebab-case and legacy snake_case statuses
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
+        match s.to_lowercase().replace('_', "-").as_str() {
             "active" => std::result::Result::Ok(Self::Active),
             "canceled" => std::result::Result::Ok(Self::Canceled),
             "canceled-by-admin" => std::result::Result::Ok(Self::CanceledByAdmin),
@@ -104,6 +158,116 @@ impl FromStr for Status {
     }
 }
 
+impl Status {
+    /// Every status an order can have
+    pub fn all() -> &'static [Status] {
+        &[
+            Status::Active,
+            Status::Canceled,
+            Status::CanceledByAdmin,
+            Status::SettledByAdmin,
+            Status::CompletedByAdmin,
+            Status::Dispute,
+            Status::Expired,
+            Status::FiatSent,
+            Status::SettledHoldInvoice,
+            Status::Pending,
+            Status::Success,
+            Status::WaitingBuyerInvoice,
+            Status::WaitingPayment,
+            Status::CooperativelyCanceled,
+        ]
+    }
+
+    /// Statuses where the order flow is over, this is the source of truth used
+    /// by `is_terminal`
+    pub fn terminal_statuses() -> &'static [Status] {
+        &[
+            Status::Success,
+            Status::Canceled,
+            Status::Expired,
+            Status::CanceledByAdmin,
+            Status::SettledByAdmin,
+            Status::CompletedByAdmin,
+            Status::CooperativelyCanceled,
+        ]
+    }
+
+    /// Check if the order can't move to another status
+    pub fn is_terminal(&self) -> bool {
+        Status::terminal_statuses().contains(self)
+    }
+}
+
+/// Deserialize through FromStr so legacy snake_case statuses are accepted too
+impl<'de> Deserialize<'de> for Status {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let status = String::deserialize(deserializer)?;
+        Status::from_str(&status)
+            .map_err(|_| serde::de::Error::custom(format!("unknown order status: {status}")))
+    }
+}
+
+/// Common payment methods, used to validate orders when strict mode is enabled
+pub const KNOWN_PAYMENT_METHODS: &[&str] = &[
+    "Bank Transfer",
+    "Bizum",
+    "Cash",
+    "Face to Face",
+    "Mercado Pago",
+    "Nequi",
+    "PayPal",
+    "Pix",
+    "Revolut",
+    "SEPA",
+    "Strike",
+    "Wise",
+    "Zelle",
+];
+
+/// Number of minor units of the currencies not using two decimals
+pub const FIAT_MINOR_UNITS: &[(&str, u32)] = &[
+    ("BHD", 3),
+    ("CLP", 0),
+    ("ISK", 0),
+    ("JOD", 3),
+    ("JPY", 0),
+    ("KRW", 0),
+    ("KWD", 3),
+    ("OMR", 3),
+    ("PYG", 0),
+    ("TND", 3),
+    ("UGX", 0),
+    ("VND", 0),
+    ("XAF", 0),
+    ("XOF", 0),
+];
+
+/// Pubkey of the key used by a user for a single trade
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct TradePubkey(pub String);
+
+impl TradePubkey {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Pubkey of the identity key of a user, shared among all their trades
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct MasterPubkey(pub String);
+
+impl MasterPubkey {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 /// Database representation of an order
 #[cfg_attr(feature = "sqlx", derive(FromRow, SqlxCrud), external_id)]
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
@@ -171,13 +335,602 @@ impl Order {
         )
     }
 
+    /// Get the typed status of the order, the status is stored as string
+    pub fn status_enum(&self) -> std::result::Result<Status, CantDoReason> {
+        Status::from_str(&self.status).map_err(|_| CantDoReason::InvalidOrderStatus)
+    }
+
     pub fn is_range_order(&self) -> bool {
         self.min_amount.is_some() && self.max_amount.is_some()
     }
+
+    /// Check if this is a range order not created from another range order
+    pub fn is_range_parent(&self) -> bool {
+        self.is_range_order() && self.range_parent_id.is_none()
+    }
+
+    /// Check if this order was created from the remainder of a range order
+    pub fn is_range_child(&self) -> bool {
+        self.range_parent_id.is_some()
+    }
+
+    /// Check that the fields that never change after creation are the same
+    /// as in a previous version of the order
+    pub fn check_immutable_fields(
+        &self,
+        previous: &Order,
+    ) -> std::result::Result<(), CantDoReason> {
+        if self.id != previous.id
+            || self.kind != previous.kind
+            || self.creator_pubkey != previous.creator_pubkey
+            || self.created_at != previous.created_at
+        {
+            return Err(CantDoReason::InvalidParameters);
+        }
+
+        std::result::Result::Ok(())
+    }
+
+    /// Check that the stored preimage hashes to the stored payment hash,
+    /// nothing is checked until both are present
+    pub fn verify_preimage(&self) -> Result<()> {
+        let (Some(hash), Some(preimage)) = (&self.hash, &self.preimage) else {
+            return Ok(());
+        };
+        let preimage = Vec::<u8>::from_hex(preimage)
+            .map_err(|_| anyhow::anyhow!("Preimage is not a valid hex string"))?;
+        if Sha256Hash::hash(&preimage).to_string() != hash.to_lowercase() {
+            return Err(anyhow::anyhow!("Preimage does not match the payment hash"));
+        }
+
+        Ok(())
+    }
+
+    /// Check if the maker can still edit the order, it must not be taken yet
+    pub fn is_editable(&self) -> bool {
+        let taken = self.buyer_pubkey.is_some() && self.seller_pubkey.is_some();
+        !taken
+            && matches!(
+                self.status_enum(),
+                std::result::Result::Ok(Status::Pending | Status::Active)
+            )
+    }
+
+    /// Apply the editable fields of the patch (amount, premium, payment method
+    /// and fiat amount) to the order, the order is left untouched when an
+    /// edited value is invalid
+    pub fn apply_edit(&mut self, patch: &SmallOrder) -> std::result::Result<(), CantDoReason> {
+        if !self.is_editable() {
+            return Err(CantDoReason::NotAllowedByStatus);
+        }
+        if patch.amount < 0 {
+            return Err(CantDoReason::InvalidAmount);
+        }
+        if patch.payment_method.trim().is_empty() {
+            return Err(CantDoReason::InvalidParameters);
+        }
+        // The range is not editable, the fiat amount must match the order's one
+        SmallOrder {
+            min_amount: self.min_amount,
+            max_amount: self.max_amount,
+            ..patch.clone()
+        }
+        .check_fiat_amount()?;
+        self.amount = patch.amount;
+        self.premium = patch.premium;
+        self.payment_method = patch.payment_method.clone();
+        self.fiat_amount = patch.fiat_amount;
+
+        std::result::Result::Ok(())
+    }
+
+    /// Set the id of the nostr event where the order was published, it must
+    /// be a 64 characters hex string
+    pub fn set_event_id(&mut self, event_id: &str) -> Result<()> {
+        if event_id.len() != 64 || !event_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow::anyhow!(
+                "Invalid event id {event_id}, expected 64 hex characters"
+            ));
+        }
+        self.event_id = event_id.to_lowercase();
+
+        Ok(())
+    }
+
+    /// Check that a market price order got its amount of sats once it was taken
+    pub fn check_priced(&self) -> std::result::Result<(), CantDoReason> {
+        let taken = matches!(
+            self.status_enum()?,
+            Status::WaitingBuyerInvoice
+                | Status::WaitingPayment
+                | Status::Active
+                | Status::FiatSent
+                | Status::SettledHoldInvoice
+                | Status::Success
+                | Status::Dispute
+        );
+        if taken && self.amount == 0 {
+            return Err(CantDoReason::InvalidAmount);
+        }
+
+        std::result::Result::Ok(())
+    }
+
+    /// Get the amount of sats of the order, market price orders are priced
+    /// with the given rate of sats per fiat unit plus the premium percentage.
+    /// Range orders are priced once taken, when their fiat amount holds the
+    /// amount chosen by the taker which must be within the range
+    pub fn effective_amount(
+        &self,
+        fiat_rate: Option<f64>,
+    ) -> std::result::Result<i64, CantDoReason> {
+        if self.amount != 0 {
+            return std::result::Result::Ok(self.amount);
+        }
+        let rate = match fiat_rate {
+            Some(rate) if rate.is_finite() && rate > 0.0 => rate,
+            _ => return Err(CantDoReason::InvalidAmount),
+        };
+        if let (Some(min), Some(max)) = (self.min_amount, self.max_amount) {
+            if self.fiat_amount == 0 {
+                return Err(CantDoReason::InvalidAmount);
+            }
+            if !(min..=max).contains(&self.fiat_amount) {
+                return Err(CantDoReason::OutOfRangeFiatAmount);
+            }
+        }
+        let amount =
+            (self.fiat_amount as f64 * rate * (1.0 + self.premium as f64 / 100.0)).round() as i64;
+
+        std::result::Result::Ok(amount)
+    }
+
+    /// Get a one line audit record of an order event as
+    /// `timestamp|id|kind|status|event|actor`, where timestamp is `now`.
+    /// No secrets are included
+    pub fn audit_record(&self, event: &str, actor: &str, now: i64) -> String {
+        let clean = |field: &str| field.replace(['|', '\n'], " ");
+        format!(
+            "{}|{}|{}|{}|{}|{}",
+            now,
+            self.id,
+            self.kind,
+            self.status,
+            clean(event),
+            clean(actor)
+        )
+    }
+
+    /// Check that the status is possible for the kind of the order:
+    ///
+    /// - `pending`: only the maker side is assigned, a sell order has a
+    ///   seller and no buyer, a buy order has a buyer and no seller
+    /// - taken statuses (from `waiting-buyer-invoice` to `success`, `dispute`
+    ///   and the statuses an admin resolves a dispute with): both sides are
+    ///   assigned
+    /// - canceled and expired statuses: any combination
+    pub fn check_kind_status_consistency(&self) -> std::result::Result<(), CantDoReason> {
+        let kind = Kind::from_str(&self.kind).map_err(|_| CantDoReason::InvalidOrderKind)?;
+        let (buyer, seller) = (self.buyer_pubkey.is_some(), self.seller_pubkey.is_some());
+        let consistent = match (self.status_enum()?, kind) {
+            (Status::Pending, Kind::Sell) => seller && !buyer,
+            (Status::Pending, Kind::Buy) => buyer && !seller,
+            (
+                Status::WaitingBuyerInvoice
+                | Status::WaitingPayment
+                | Status::Active
+                | Status::FiatSent
+                | Status::SettledHoldInvoice
+                | Status::Success
+                | Status::Dispute
+                | Status::SettledByAdmin
+                | Status::CompletedByAdmin,
+                _,
+            ) => buyer && seller,
+            (
+                Status::Canceled
+                | Status::CanceledByAdmin
+                | Status::CooperativelyCanceled
+                | Status::Expired,
+                _,
+            ) => true,
+        };
+        if !consistent {
+            return Err(CantDoReason::InvalidOrderStatus);
+        }
+
+        std::result::Result::Ok(())
+    }
+
+    /// Move the order to a new status, only the transitions listed in
+    /// `allowed_transitions` are accepted
+    pub fn try_transition(&mut self, to: Status) -> std::result::Result<(), CantDoReason> {
+        let from = self.status_enum()?;
+        if !allowed_transitions(from).contains(&to) {
+            return Err(CantDoReason::NotAllowedByStatus);
+        }
+        self.status = to.to_string();
+
+        std::result::Result::Ok(())
+    }
+
+    /// Check if the seller can still pay the hold invoice, only possible
+    /// while the order is waiting for the payment
+    pub fn can_pay_invoice(&self) -> std::result::Result<(), CantDoReason> {
+        match self.status_enum()? {
+            Status::WaitingPayment => std::result::Result::Ok(()),
+            Status::Canceled
+            | Status::CanceledByAdmin
+            | Status::CooperativelyCanceled
+            | Status::Expired => Err(CantDoReason::OrderAlreadyCanceled),
+            _ => Err(CantDoReason::NotAllowedByStatus),
+        }
+    }
+
+    /// Get the creator pubkey formatted as bech32 npub, it can be stored as
+    /// hex or npub
+    pub fn creator_npub(&self) -> std::result::Result<String, CantDoReason> {
+        PublicKey::parse(&self.creator_pubkey)
+            .ok()
+            .and_then(|pubkey| pubkey.to_bech32().ok())
+            .ok_or(CantDoReason::InvalidPubkey)
+    }
+
+    /// Get the trade pubkey of the given role, None if that side is not assigned yet
+    pub fn role_pubkey(&self, role: Role) -> Option<&str> {
+        match role {
+            Role::Buyer => self.buyer_pubkey.as_deref(),
+            Role::Seller => self.seller_pubkey.as_deref(),
+        }
+    }
+
+    /// Get the typed trade pubkey of the given role
+    pub fn trade_pubkey(&self, role: Role) -> Option<TradePubkey> {
+        self.role_pubkey(role)
+            .map(|pubkey| TradePubkey(pubkey.to_string()))
+    }
+
+    /// Get the typed master pubkey of the given role
+    pub fn master_pubkey(&self, role: Role) -> Option<MasterPubkey> {
+        match role {
+            Role::Buyer => self.master_buyer_pubkey.clone().map(MasterPubkey),
+            Role::Seller => self.master_seller_pubkey.clone().map(MasterPubkey),
+        }
+    }
+
+    /// Check if the reputation of the given role can be shown, users in full
+    /// privacy mode trade without an identity key, so their master pubkey is
+    /// missing or is the trade pubkey itself
+    pub fn show_reputation(&self, role: Role) -> bool {
+        match (self.master_pubkey(role), self.trade_pubkey(role)) {
+            (Some(master), Some(trade)) => master.as_str() != trade.as_str(),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Set the trade pubkey of the given role
+    pub fn set_role_pubkey(&mut self, role: Role, pubkey: String) {
+        match role {
+            Role::Buyer => self.buyer_pubkey = Some(pubkey),
+            Role::Seller => self.seller_pubkey = Some(pubkey),
+        }
+    }
+
+    /// Get the remaining time until the order expires as a short string like
+    /// `14m`, `2h 5m` or `1d 3h`
+    pub fn expiry_human(&self, now: i64) -> String {
+        if self.expires_at == 0 {
+            return "never".to_string();
+        }
+        let remaining = self.expires_at - now;
+        if remaining <= 0 {
+            return "expired".to_string();
+        }
+        let (days, hours, minutes) = (
+            remaining / 86400,
+            remaining % 86400 / 3600,
+            remaining % 3600 / 60,
+        );
+        if days > 0 {
+            format!("{days}d {hours}h")
+        } else if hours > 0 {
+            format!("{hours}h {minutes}m")
+        } else {
+            format!("{minutes}m")
+        }
+    }
+
+    /// Get the role of the party who opened the dispute, None if the order
+    /// was not disputed. Both flags set is an invalid state
+    pub fn dispute_initiator_role(&self) -> std::result::Result<Option<Role>, CantDoReason> {
+        match (self.buyer_dispute, self.seller_dispute) {
+            (true, true) => Err(CantDoReason::InvalidParameters),
+            (true, false) => std::result::Result::Ok(Some(Role::Buyer)),
+            (false, true) => std::result::Result::Ok(Some(Role::Seller)),
+            (false, false) => std::result::Result::Ok(None),
+        }
+    }
+
+    /// Get the reason why an action can't be performed on the order in its
+    /// current status, None when the action is allowed or not tied to a status
+    pub fn reason_for_disallowed(&self, action: &Action) -> Option<CantDoReason> {
+        let status = match self.status_enum() {
+            std::result::Result::Ok(status) => status,
+            Err(reason) => return Some(reason),
+        };
+        let allowed: &[Status] = match action {
+            Action::TakeSell | Action::TakeBuy => {
+                let kind = if *action == Action::TakeSell {
+                    Kind::Sell
+                } else {
+                    Kind::Buy
+                };
+                if Kind::from_str(&self.kind).ok() != Some(kind) {
+                    return Some(CantDoReason::InvalidOrderKind);
+                }
+                &[Status::Pending]
+            }
+            Action::Cancel => {
+                if matches!(
+                    status,
+                    Status::Canceled | Status::CanceledByAdmin | Status::CooperativelyCanceled
+                ) {
+                    return Some(CantDoReason::OrderAlreadyCanceled);
+                }
+                &[
+                    Status::Pending,
+                    Status::WaitingBuyerInvoice,
+                    Status::WaitingPayment,
+                    Status::Active,
+                    Status::FiatSent,
+                ]
+            }
+            Action::AddInvoice => &[Status::WaitingBuyerInvoice, Status::SettledHoldInvoice],
+            Action::FiatSent => &[Status::Active],
+            Action::Release => &[Status::Active, Status::FiatSent, Status::Dispute],
+            Action::Dispute => &[Status::Active, Status::FiatSent],
+            Action::RateUser => &[Status::SettledHoldInvoice, Status::Success],
+            _ => return None,
+        };
+        if allowed.contains(&status) {
+            None
+        } else {
+            Some(CantDoReason::NotAllowedByStatus)
+        }
+    }
+
+    /// Check if the order exceeded the timeout of its current status,
+    /// pending orders are measured from creation and waiting orders from
+    /// the moment they were taken
+    pub fn is_stuck(&self, now: i64, thresholds: &StuckThresholds) -> bool {
+        let (since, timeout) = match self.status_enum() {
+            std::result::Result::Ok(Status::Pending) => (self.created_at, thresholds.pending),
+            std::result::Result::Ok(Status::WaitingPayment) => {
+                (self.taken_at, thresholds.waiting_payment)
+            }
+            std::result::Result::Ok(Status::WaitingBuyerInvoice) => {
+                (self.taken_at, thresholds.waiting_buyer_invoice)
+            }
+            _ => return false,
+        };
+
+        now - since > timeout
+    }
+}
+
+/// Get the statuses an order can move to from the given one, terminal
+/// statuses as listed in `Status::terminal_statuses` have no transitions
+pub fn allowed_transitions(from: Status) -> &'static [Status] {
+    match from {
+        Status::Pending => &[
+            Status::WaitingPayment,
+            Status::WaitingBuyerInvoice,
+            Status::Canceled,
+            Status::Expired,
+        ],
+        Status::WaitingPayment => &[
+            Status::WaitingBuyerInvoice,
+            Status::Active,
+            Status::Pending,
+            Status::Canceled,
+        ],
+        Status::WaitingBuyerInvoice => &[
+            Status::WaitingPayment,
+            Status::Active,
+            Status::Pending,
+            Status::Canceled,
+        ],
+        Status::Active => &[
+            Status::FiatSent,
+            Status::SettledHoldInvoice,
+            Status::Dispute,
+            Status::CooperativelyCanceled,
+        ],
+        Status::FiatSent => &[
+            Status::SettledHoldInvoice,
+            Status::Dispute,
+            Status::CooperativelyCanceled,
+        ],
+        Status::SettledHoldInvoice => &[Status::Success],
+        Status::Dispute => &[
+            Status::SettledHoldInvoice,
+            Status::SettledByAdmin,
+            Status::CompletedByAdmin,
+            Status::CanceledByAdmin,
+        ],
+        Status::Canceled
+        | Status::CanceledByAdmin
+        | Status::SettledByAdmin
+        | Status::CompletedByAdmin
+        | Status::CooperativelyCanceled
+        | Status::Expired
+        | Status::Success => &[],
+    }
+}
+
+/// Get the orders where the user takes part, matching either its trade or
+/// master pubkey. The trade index of the counterparty is cleared so the result
+/// can be passed to `check_unique_trade_indices`
+pub fn orders_of_user(orders: &[Order], pubkey: &str) -> Vec<Order> {
+    let is_user =
+        |keys: [&Option<String>; 2]| keys.iter().any(|key| key.as_deref() == Some(pubkey));
+    orders
+        .iter()
+        .filter_map(|order| {
+            let mut order = order.clone();
+            if is_user([&order.buyer_pubkey, &order.master_buyer_pubkey]) {
+                order.trade_index_seller = None;
+            } else if is_user([&order.seller_pubkey, &order.master_seller_pubkey]) {
+                order.trade_index_buyer = None;
+            } else {
+                return None;
+            }
+            Some(order)
+        })
+        .collect()
+}
+
+/// Check that no trade index is used by more than one of the active orders
+/// of a user, a repeated index could be a replayed message. Terminal orders
+/// are skipped; the orders must belong to a single user, see `orders_of_user`
+pub fn check_unique_trade_indices(orders: &[Order]) -> std::result::Result<(), CantDoReason> {
+    let mut seen = HashSet::new();
+    for order in orders {
+        if order.status_enum().is_ok_and(|status| status.is_terminal()) {
+            continue;
+        }
+        for index in [order.trade_index_buyer, order.trade_index_seller]
+            .into_iter()
+            .flatten()
+        {
+            if !seen.insert(index) {
+                return Err(CantDoReason::InvalidTradeIndex);
+            }
+        }
+    }
+
+    std::result::Result::Ok(())
+}
+
+/// Sum the amount of the orders with sats locked in a hold invoice, those are
+/// orders in `active`, `fiat-sent`, `dispute` or `settled-hold-invoice` status
+/// and `waiting-buyer-invoice` orders whose hold invoice was already paid, as
+/// in the take buy flow
+pub fn total_locked_sats(orders: &[Order]) -> i64 {
+    orders
+        .iter()
+        .filter(|order| match Status::from_str(&order.status) {
+            std::result::Result::Ok(
+                Status::Active | Status::FiatSent | Status::Dispute | Status::SettledHoldInvoice,
+            ) => true,
+            std::result::Result::Ok(Status::WaitingBuyerInvoice) => order.invoice_held_at != 0,
+            _ => false,
+        })
+        .map(|order| order.amount)
+        .sum()
+}
+
+/// Summary of a set of orders for analytics
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+pub struct OrderStats {
+    /// Number of orders
+    pub total: usize,
+    /// Number of orders in each status, keyed by the status string
+    pub by_status: BTreeMap<String, usize>,
+    /// Share of orders completed, `success` or `completed-by-admin`, from 0 to 1
+    pub completion_rate: f64,
+    /// Sum of the amount of all orders in sats, range and market price orders
+    /// without a fixed amount count as 0
+    pub total_amount: i64,
+    /// Median of the fiat amount in each currency, keyed by the uppercase fiat
+    /// code, as amounts in different currencies can't be compared
+    pub median_fiat_amount: BTreeMap<String, i64>,
+}
+
+impl OrderStats {
+    pub fn compute(orders: &[Order]) -> Self {
+        let mut stats = Self {
+            total: orders.len(),
+            ..Default::default()
+        };
+        let mut completed = 0;
+        let mut fiat_amounts: BTreeMap<String, Vec<i64>> = BTreeMap::new();
+        for order in orders {
+            *stats.by_status.entry(order.status.clone()).or_default() += 1;
+            if matches!(
+                order.status_enum(),
+                std::result::Result::Ok(Status::Success | Status::CompletedByAdmin)
+            ) {
+                completed += 1;
+            }
+            stats.total_amount += order.amount;
+            fiat_amounts
+                .entry(order.fiat_code.trim().to_uppercase())
+                .or_default()
+                .push(order.fiat_amount);
+        }
+        if !orders.is_empty() {
+            stats.completion_rate = completed as f64 / orders.len() as f64;
+        }
+        for (fiat_code, mut amounts) in fiat_amounts {
+            amounts.sort_unstable();
+            let middle = amounts.len() / 2;
+            let median = if amounts.len() % 2 == 0 {
+                (amounts[middle - 1] + amounts[middle]) / 2
+            } else {
+                amounts[middle]
+            };
+            stats.median_fiat_amount.insert(fiat_code, median);
+        }
+
+        stats
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl Order {
+    /// Stream the orders created by a user, rows are fetched as the stream is polled
+    pub fn stream_by_creator<'a>(
+        pool: &'a SqlitePool,
+        pubkey: &'a str,
+    ) -> impl Stream<Item = Result<Order>> + 'a {
+        sqlx::query_as::<_, Order>("SELECT * FROM orders WHERE creator_pubkey = ?")
+            .bind(pubkey)
+            .fetch(pool)
+            .map(|row| row.map_err(anyhow::Error::from))
+    }
+
+    /// Get a page of the orders in a status, newest first
+    pub async fn read_by_status(
+        pool: &SqlitePool,
+        status: Status,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Order>> {
+        let orders = sqlx::query_as::<_, Order>(
+            "SELECT * FROM orders WHERE status = ? ORDER BY created_at DESC LIMIT ? OFFSET ?",
+        )
+        .bind(status.to_string())
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(orders)
+    }
+}
+
+/// Timeouts in seconds after which an order in each status is considered stuck
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct StuckThresholds {
+    pub pending: i64,
+    pub waiting_payment: i64,
+    pub waiting_buyer_invoice: i64,
 }
 
 /// We use this struct to create a new order
-#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
 pub struct SmallOrder {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<Uuid>,
@@ -204,6 +957,8 @@ pub struct SmallOrder {
 
 #[allow(dead_code)]
 impl SmallOrder {
+    /// New order from all its fields, prefer `SmallOrder::builder` which
+    /// validates the order and avoids misordered arguments
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: Option<Uuid>,
@@ -244,6 +999,13 @@ impl SmallOrder {
             seller_token,
         }
     }
+    /// Start building an order, every field is empty
+    pub fn builder() -> SmallOrderBuilder {
+        SmallOrderBuilder {
+            order: Self::default(),
+        }
+    }
+
     /// New order from json string
     pub fn from_json(json: &str) -> Result<Self> {
         Ok(serde_json::from_str(json)?)
@@ -254,6 +1016,284 @@ impl SmallOrder {
         Ok(serde_json::to_string(&self)?)
     }
 
+    /// Transform NIP-69 order event tags to SmallOrder
+    pub fn from_tags(tags: Tags) -> Result<Self> {
+        let mut order = Self::default();
+
+        for tag in tags.into_iter() {
+            let t = tag.to_vec();
+            // Marker tags like NIP-70 `-` have no value and are not order fields
+            let (Some(key), Some(value)) = (t.first(), t.get(1)) else {
+                continue;
+            };
+            let values = &t[1..];
+            match key.as_str() {
+                "d" => order.id = Some(Uuid::parse_str(value)?),
+                "k" => {
+                    order.kind = Some(
+                        Kind::from_str(value)
+                            .map_err(|_| anyhow::anyhow!("Invalid order kind {value}"))?,
+                    )
+                }
+                "s" => {
+                    order.status = Some(
+                        Status::from_str(value)
+                            .map_err(|_| anyhow::anyhow!("Invalid order status {value}"))?,
+                    )
+                }
+                "f" => order.fiat_code = value.to_string(),
+                "amt" => order.amount = value.parse::<i64>()?,
+                "fa" => {
+                    if let [min, max] = values {
+                        order.min_amount = Some(min.parse::<i64>()?);
+                        order.max_amount = Some(max.parse::<i64>()?);
+                    } else {
+                        order.fiat_amount = value.parse::<i64>()?;
+                    }
+                }
+                "pm" => order.payment_method = values.join(","),
+                "premium" => order.premium = value.parse::<i64>()?,
+                "expiration" => order.expires_at = Some(value.parse::<i64>()?),
+                _ => {}
+            }
+        }
+        if order.id.is_none() {
+            return Err(anyhow::anyhow!("Missing order id"));
+        }
+
+        Ok(order)
+    }
+
+    /// Parse a batch of order events, each event keeps its own result and
+    /// orders are dated with the creation time of their event
+    pub fn from_events(events: &[Event]) -> Vec<Result<Self>> {
+        events
+            .iter()
+            .map(|event| {
+                let mut order = Self::from_tags(event.tags.clone())?;
+                order.created_at = Some(event.created_at.as_u64() as i64);
+                Ok(order)
+            })
+            .collect()
+    }
+
+    /// Get order as canonical json string to be signed, keys are sorted and
+    /// optional fields are always present as null
+    pub fn canonical_json(&self) -> Result<String> {
+        let value = serde_json::to_value(self)?;
+        let mut fields: BTreeMap<String, serde_json::Value> = match value {
+            serde_json::Value::Object(map) => map.into_iter().collect(),
+            _ => return Err(anyhow::anyhow!("Order is not a json object")),
+        };
+        for key in [
+            "id",
+            "buyer_trade_pubkey",
+            "seller_trade_pubkey",
+            "buyer_invoice",
+        ] {
+            fields
+                .entry(key.to_string())
+                .or_insert(serde_json::Value::Null);
+        }
+
+        Ok(serde_json::to_string(&fields)?)
+    }
+
+    /// Trim and uppercase the fiat code, ISO 4217 codes have no whitespace inside
+    pub fn normalize_fiat_code(&mut self) -> std::result::Result<(), CantDoReason> {
+        self.fiat_code = normalized_fiat_code(&self.fiat_code)?;
+        std::result::Result::Ok(())
+    }
+
+    /// Set the buyer invoice normalized with `normalize_invoice`, lightning
+    /// addresses are only trimmed
+    pub fn set_buyer_invoice(&mut self, raw: &str) -> std::result::Result<(), CantDoReason> {
+        self.buyer_invoice = Some(normalize_payment_request(raw)?);
+        std::result::Result::Ok(())
+    }
+
+    /// Check that a fixed amount buyer invoice requests exactly the order amount,
+    /// amountless invoices and lightning addresses are accepted
+    pub fn validate_invoice_amount(&self) -> std::result::Result<(), CantDoReason> {
+        let invoice = match &self.buyer_invoice {
+            Some(invoice) if self.amount != 0 && !invoice.contains('@') => invoice,
+            _ => return std::result::Result::Ok(()),
+        };
+        let (_, invoice) = parse_invoice(invoice)?;
+        match invoice.amount_milli_satoshis() {
+            Some(msat) if msat != (self.amount as u64).saturating_mul(1000) => {
+                Err(CantDoReason::InvalidAmount)
+            }
+            _ => std::result::Result::Ok(()),
+        }
+    }
+
+    /// Check that the buyer invoice does not expire before the order, it is
+    /// skipped when the order has no invoice or no expiration
+    pub fn check_invoice_outlives_order(&self, now: i64) -> std::result::Result<(), CantDoReason> {
+        let (invoice, order_expires_at) = match (&self.buyer_invoice, self.expires_at) {
+            (Some(invoice), Some(expires_at)) if !invoice.contains('@') => (invoice, expires_at),
+            _ => return std::result::Result::Ok(()),
+        };
+        let (_, invoice) = parse_invoice(invoice)?;
+        let invoice_expires_at = invoice
+            .expires_at()
+            .map(|expires_at| expires_at.as_secs() as i64)
+            .ok_or(CantDoReason::InvalidInvoice)?;
+        if invoice_expires_at <= now || invoice_expires_at < order_expires_at {
+            return Err(CantDoReason::InvalidInvoice);
+        }
+
+        std::result::Result::Ok(())
+    }
+
+    /// Check that a fixed amount order is not below the lightning dust limit,
+    /// market price orders are skipped
+    pub fn check_above_dust(&self, dust_sats: i64) -> std::result::Result<(), CantDoReason> {
+        if self.amount != 0 && self.amount < dust_sats {
+            return Err(CantDoReason::OutOfRangeSatsAmount);
+        }
+
+        std::result::Result::Ok(())
+    }
+
+    /// Check that every comma separated payment method is a known one, any
+    /// method is accepted when not in strict mode
+    pub fn check_known_payment_methods(
+        &self,
+        strict: bool,
+    ) -> std::result::Result<(), CantDoReason> {
+        if !strict {
+            return std::result::Result::Ok(());
+        }
+        let known = |method: &str| {
+            KNOWN_PAYMENT_METHODS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(method))
+        };
+        if !self.payment_method.split(',').map(str::trim).all(known) {
+            return Err(CantDoReason::InvalidParameters);
+        }
+
+        std::result::Result::Ok(())
+    }
+
+    /// Check that the premium is within the band allowed by the platform,
+    /// both limits are inclusive
+    pub fn check_premium_band(&self, min: i64, max: i64) -> std::result::Result<(), CantDoReason> {
+        if !(min..=max).contains(&self.premium) {
+            return Err(CantDoReason::InvalidParameters);
+        }
+
+        std::result::Result::Ok(())
+    }
+
+    /// Get the public searchable fields of the order as strings, for indexers
+    pub fn to_index_map(&self) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        if let Some(kind) = self.kind {
+            map.insert("kind".to_string(), kind.to_string());
+        }
+        if let Some(status) = self.status {
+            map.insert("status".to_string(), status.to_string());
+        }
+        map.insert("fiat_code".to_string(), self.fiat_code.clone());
+        map.insert("payment_method".to_string(), self.payment_method.clone());
+        map.insert("premium".to_string(), self.premium.to_string());
+        map.insert("amount".to_string(), self.amount.to_string());
+
+        map
+    }
+
+    /// Get the fiat amount of the trade, for range orders it is the amount
+    /// chosen by the taker, which must be within the range
+    pub fn effective_fiat_amount(
+        &self,
+        taken: Option<i64>,
+    ) -> std::result::Result<i64, CantDoReason> {
+        let (Some(min), Some(max)) = (self.min_amount, self.max_amount) else {
+            return std::result::Result::Ok(self.fiat_amount);
+        };
+        match taken {
+            Some(amount) if (min..=max).contains(&amount) => std::result::Result::Ok(amount),
+            Some(_) => Err(CantDoReason::OutOfRangeFiatAmount),
+            None => Err(CantDoReason::InvalidAmount),
+        }
+    }
+
+    /// Check the fiat amount, fixed orders need a positive amount while range
+    /// orders have none until they are taken
+    pub fn check_fiat_amount(&self) -> std::result::Result<(), CantDoReason> {
+        let is_range = self.min_amount.is_some() && self.max_amount.is_some();
+        let valid = if is_range {
+            self.fiat_amount == 0
+        } else {
+            self.fiat_amount > 0
+        };
+        if !valid {
+            return Err(CantDoReason::OutOfRangeFiatAmount);
+        }
+
+        std::result::Result::Ok(())
+    }
+
+    /// Check if both orders are the same one, compared by id when both have
+    /// it and by their content otherwise
+    pub fn is_duplicate_of(&self, other: &SmallOrder) -> bool {
+        if let (Some(id), Some(other_id)) = (self.id, other.id) {
+            return id == other_id;
+        }
+        self.kind == other.kind
+            && self.amount == other.amount
+            && self.min_amount == other.min_amount
+            && self.max_amount == other.max_amount
+            && self.fiat_amount == other.fiat_amount
+            && self.fiat_code.eq_ignore_ascii_case(&other.fiat_code)
+            && self.payment_method == other.payment_method
+            && self.created_at == other.created_at
+    }
+
+    /// Get the fiat amount in major units, the stored amount is in minor
+    /// units of the currency, e.g. `100` USD is `1.00` and `100` JPY is `100`
+    pub fn fiat_amount_display(&self) -> String {
+        let code = normalized_fiat_code(&self.fiat_code).unwrap_or_default();
+        let decimals = FIAT_MINOR_UNITS
+            .iter()
+            .find(|(fiat_code, _)| *fiat_code == code)
+            .map_or(2, |(_, decimals)| *decimals);
+        if decimals == 0 {
+            return self.fiat_amount.to_string();
+        }
+        let units = 10_u64.pow(decimals);
+        let amount = self.fiat_amount.unsigned_abs();
+        let sign = if self.fiat_amount < 0 { "-" } else { "" };
+        format!(
+            "{sign}{}.{:0width$}",
+            amount / units,
+            amount % units,
+            width = decimals as usize
+        )
+    }
+
+    /// Get how long ago the order was created as a short string like
+    /// `just now`, `14m ago`, `3h ago` or `2d ago`
+    pub fn age_human(&self, now: i64) -> String {
+        let created_at = match self.created_at {
+            Some(created_at) if created_at > 0 => created_at,
+            _ => return "unknown".to_string(),
+        };
+        let age = now - created_at;
+        if age < 60 {
+            "just now".to_string()
+        } else if age < 3600 {
+            format!("{}m ago", age / 60)
+        } else if age < 86400 {
+            format!("{}h ago", age / 3600)
+        } else {
+            format!("{}d ago", age / 86400)
+        }
+    }
+
     // Get the amount of sats or the string "Market price"
     pub fn sats_amount(&self) -> String {
         if self.amount == 0 {
@@ -271,4 +1311,239 @@ impl SmallOrder {
             self.fiat_amount.to_string()
         }
     }
+
+    /// Apply a partial update, only the fields set in the patch are changed
+    pub fn merge_patch(&mut self, patch: &SmallOrderPatch) {
+        if let Some(status) = patch.status {
+            self.status = Some(status);
+        }
+        if let Some(amount) = patch.amount {
+            self.amount = amount;
+        }
+        if let Some(fiat_code) = &patch.fiat_code {
+            self.fiat_code = fiat_code.clone();
+        }
+        if let Some(min_amount) = patch.min_amount {
+            self.min_amount = Some(min_amount);
+        }
+        if let Some(max_amount) = patch.max_amount {
+            self.max_amount = Some(max_amount);
+        }
+        if let Some(fiat_amount) = patch.fiat_amount {
+            self.fiat_amount = fiat_amount;
+        }
+        if let Some(payment_method) = &patch.payment_method {
+            self.payment_method = payment_method.clone();
+        }
+        if let Some(premium) = patch.premium {
+            self.premium = premium;
+        }
+        if let Some(buyer_invoice) = &patch.buyer_invoice {
+            self.buyer_invoice = Some(buyer_invoice.clone());
+        }
+        if let Some(expires_at) = patch.expires_at {
+            self.expires_at = Some(expires_at);
+        }
+    }
+}
+
+/// Builder of orders where only the needed fields are set
+#[derive(Debug)]
+pub struct SmallOrderBuilder {
+    order: SmallOrder,
+}
+
+impl SmallOrderBuilder {
+    pub fn id(mut self, id: Uuid) -> Self {
+        self.order.id = Some(id);
+        self
+    }
+
+    pub fn kind(mut self, kind: Kind) -> Self {
+        self.order.kind = Some(kind);
+        self
+    }
+
+    pub fn status(mut self, status: Status) -> Self {
+        self.order.status = Some(status);
+        self
+    }
+
+    pub fn amount(mut self, amount: i64) -> Self {
+        self.order.amount = amount;
+        self
+    }
+
+    pub fn fiat_code(mut self, fiat_code: &str) -> Self {
+        self.order.fiat_code = fiat_code.to_string();
+        self
+    }
+
+    pub fn fiat_amount(mut self, fiat_amount: i64) -> Self {
+        self.order.fiat_amount = fiat_amount;
+        self
+    }
+
+    pub fn range(mut self, min_amount: i64, max_amount: i64) -> Self {
+        self.order.min_amount = Some(min_amount);
+        self.order.max_amount = Some(max_amount);
+        self
+    }
+
+    pub fn payment_method(mut self, payment_method: &str) -> Self {
+        self.order.payment_method = payment_method.to_string();
+        self
+    }
+
+    pub fn premium(mut self, premium: i64) -> Self {
+        self.order.premium = premium;
+        self
+    }
+
+    pub fn buyer_trade_pubkey(mut self, pubkey: String) -> Self {
+        self.order.buyer_trade_pubkey = Some(pubkey);
+        self
+    }
+
+    pub fn seller_trade_pubkey(mut self, pubkey: String) -> Self {
+        self.order.seller_trade_pubkey = Some(pubkey);
+        self
+    }
+
+    pub fn buyer_invoice(mut self, invoice: String) -> Self {
+        self.order.buyer_invoice = Some(invoice);
+        self
+    }
+
+    pub fn created_at(mut self, created_at: i64) -> Self {
+        self.order.created_at = Some(created_at);
+        self
+    }
+
+    pub fn expires_at(mut self, expires_at: i64) -> Self {
+        self.order.expires_at = Some(expires_at);
+        self
+    }
+
+    /// Get the order, the sats amount can't be negative, the fiat code must be
+    /// set and the order is either fixed or a valid range
+    pub fn build(self) -> std::result::Result<SmallOrder, CantDoReason> {
+        let mut order = self.order;
+        if order.amount < 0 {
+            return Err(CantDoReason::InvalidAmount);
+        }
+        normalized_fiat_code(&order.fiat_code)?;
+        if let (Some(min), Some(max)) = (order.min_amount, order.max_amount) {
+            if min <= 0 || min >= max {
+                return Err(CantDoReason::OutOfRangeFiatAmount);
+            }
+        }
+        order.check_fiat_amount()?;
+        if let Some(invoice) = order.buyer_invoice.take() {
+            order.set_buyer_invoice(&invoice)?;
+        }
+
+        std::result::Result::Ok(order)
+    }
+}
+
+/// Partial update of a SmallOrder, None fields are left untouched
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SmallOrderPatch {
+    pub status: Option<Status>,
+    pub amount: Option<i64>,
+    pub fiat_code: Option<String>,
+    pub min_amount: Option<i64>,
+    pub max_amount: Option<i64>,
+    pub fiat_amount: Option<i64>,
+    pub payment_method: Option<String>,
+    pub premium: Option<i64>,
+    pub buyer_invoice: Option<String>,
+    pub expires_at: Option<i64>,
+}
+
+/// Strip the `lightning:` URI prefix and surrounding whitespace from an invoice,
+/// the result is the lowercase invoice which must be a valid Bolt11 invoice
+pub fn normalize_invoice(raw: &str) -> std::result::Result<String, CantDoReason> {
+    parse_invoice(raw).map(|(invoice, _)| invoice)
+}
+
+/// Normalize a payment request that is either a bolt11 invoice or a
+/// lightning address, addresses are only trimmed
+pub(crate) fn normalize_payment_request(raw: &str) -> std::result::Result<String, CantDoReason> {
+    let raw = raw.trim();
+    if raw.contains('@') {
+        return std::result::Result::Ok(raw.to_string());
+    }
+    normalize_invoice(raw)
+}
+
+fn parse_invoice(raw: &str) -> std::result::Result<(String, Bolt11Invoice), CantDoReason> {
+    let invoice = raw.trim();
+    let invoice = match invoice.get(..10) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("lightning:") => &invoice[10..],
+        _ => invoice,
+    };
+    let invoice = invoice.trim().to_lowercase();
+    let parsed = Bolt11Invoice::from_str(&invoice).map_err(|_| CantDoReason::InvalidInvoice)?;
+
+    std::result::Result::Ok((invoice, parsed))
+}
+
+fn normalized_fiat_code(code: &str) -> std::result::Result<String, CantDoReason> {
+    let code = code.trim();
+    if code.is_empty() || code.chars().any(char::is_whitespace) {
+        return Err(CantDoReason::InvalidFiatCurrency);
+    }
+
+    std::result::Result::Ok(code.to_uppercase())
+}
+
+/// Get the sorted list of fiat codes used in the orders, codes are normalized
+/// to uppercase and invalid ones are skipped
+pub fn distinct_fiat_codes(orders: &[SmallOrder]) -> Vec<String> {
+    orders
+        .iter()
+        .filter_map(|order| normalized_fiat_code(&order.fiat_code).ok())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect()
+}
+
+/// Changes between two snapshots of the order book
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+pub struct OrderBookDelta {
+    pub added: Vec<SmallOrder>,
+    pub removed: Vec<Uuid>,
+    pub updated: Vec<SmallOrder>,
+}
+
+impl OrderBookDelta {
+    /// Compute the changes from the old to the new snapshot, orders are
+    /// matched by id and orders without id are ignored
+    pub fn diff(old: &[SmallOrder], new: &[SmallOrder]) -> Self {
+        let old_by_id: HashMap<Uuid, &SmallOrder> = old
+            .iter()
+            .filter_map(|order| order.id.map(|id| (id, order)))
+            .collect();
+        let new_ids: HashSet<Uuid> = new.iter().filter_map(|order| order.id).collect();
+        let mut delta = Self::default();
+        for order in new {
+            let Some(id) = order.id else {
+                continue;
+            };
+            match old_by_id.get(&id) {
+                None => delta.added.push(order.clone()),
+                Some(previous) if *previous != order => delta.updated.push(order.clone()),
+                Some(_) => {}
+            }
+        }
+        delta.removed = old
+            .iter()
+            .filter_map(|order| order.id)
+            .filter(|id| !new_ids.contains(id))
+            .collect();
+
+        delta
+    }
 }
@@ -1,3 +1,4 @@
+use crate::message::CantDoReason;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "sqlx")]
@@ -21,6 +22,22 @@ pub struct User {
     pub max_rating: i64,
     pub min_rating: i64,
     pub created_at: i64,
+    /// Number of received ratings of each value, from one to five stars
+    #[serde(default)]
+    #[cfg_attr(feature = "sqlx", sqlx(default))]
+    pub rating_count_1: i64,
+    #[serde(default)]
+    #[cfg_attr(feature = "sqlx", sqlx(default))]
+    pub rating_count_2: i64,
+    #[serde(default)]
+    #[cfg_attr(feature = "sqlx", sqlx(default))]
+    pub rating_count_3: i64,
+    #[serde(default)]
+    #[cfg_attr(feature = "sqlx", sqlx(default))]
+    pub rating_count_4: i64,
+    #[serde(default)]
+    #[cfg_attr(feature = "sqlx", sqlx(default))]
+    pub rating_count_5: i64,
 }
 
 impl User {
@@ -45,6 +62,89 @@ impl User {
             max_rating: 0,
             min_rating: 0,
             created_at: Utc::now().timestamp(),
+            rating_count_1: 0,
+            rating_count_2: 0,
+            rating_count_3: 0,
+            rating_count_4: 0,
+            rating_count_5: 0,
         }
     }
+
+    /// Get the rating distribution, the first bucket counts one star ratings
+    pub fn rating_histogram(&self) -> [u64; 5] {
+        [
+            self.rating_count_1,
+            self.rating_count_2,
+            self.rating_count_3,
+            self.rating_count_4,
+            self.rating_count_5,
+        ]
+        .map(|count| count.max(0) as u64)
+    }
+
+    /// Record a new rating and recompute the average from the histogram.
+    /// Reviews received before the histogram existed keep weighing in with
+    /// the stars they added to the stored average
+    pub fn record_rating(&mut self, rating: u8) -> Result<(), CantDoReason> {
+        let bucket = match rating {
+            1 => &mut self.rating_count_1,
+            2 => &mut self.rating_count_2,
+            3 => &mut self.rating_count_3,
+            4 => &mut self.rating_count_4,
+            5 => &mut self.rating_count_5,
+            _ => return Err(CantDoReason::InvalidRating),
+        };
+        *bucket += 1;
+
+        let histogram = self.rating_histogram();
+        let rated: u64 = histogram.iter().sum();
+        let stars: u64 = histogram
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (i as u64 + 1) * count)
+            .sum();
+        // The stored average covers legacy and histogram reviews, so the
+        // legacy sum is what remains after removing the previous stars
+        let previous_reviews = self.total_reviews.max(0) as u64;
+        let legacy_reviews = previous_reviews.saturating_sub(rated - 1);
+        let legacy_stars = if legacy_reviews > 0 {
+            let previous_stars = stars - rating as u64;
+            (self.total_rating * previous_reviews as f64 - previous_stars as f64).max(0.0)
+        } else {
+            0.0
+        };
+        let total_reviews = legacy_reviews + rated;
+        self.total_rating = (legacy_stars + stars as f64) / total_reviews as f64;
+        self.total_reviews = total_reviews as i64;
+
+        let rating = rating as i64;
+        if self.min_rating == 0 || rating < self.min_rating {
+            self.min_rating = rating;
+        }
+        if rating > self.max_rating {
+            self.max_rating = rating;
+        }
+        self.last_rating = rating;
+
+        Ok(())
+    }
+
+    /// Get the rating decayed toward the neutral 3 stars for a user inactive
+    /// for `inactive_days`, halving the distance every `half_life_days`:
+    ///
+    /// `3 + (total_rating - 3) * 0.5 ^ (inactive_days / half_life_days)`
+    ///
+    /// Users without reviews get the neutral rating and a half life of zero
+    /// disables the decay. It's advisory, the stored rating is not modified
+    pub fn decayed_rating(&self, inactive_days: u64, half_life_days: u64) -> f64 {
+        const NEUTRAL_RATING: f64 = 3.0;
+        if self.total_reviews <= 0 {
+            return NEUTRAL_RATING;
+        }
+        if half_life_days == 0 {
+            return self.total_rating;
+        }
+        let decay = 0.5_f64.powf(inactive_days as f64 / half_life_days as f64);
+        NEUTRAL_RATING + (self.total_rating - NEUTRAL_RATING) * decay
+    }
 }